@@ -8,7 +8,10 @@ export enum MultiplayerEvent {
   PING = 'Ping',
   JOIN_GAME = 'JoinGame',
   START_GAME = 'StartGame',
-  CREATE_GAME = 'CreateGame'
+  CREATE_GAME = 'CreateGame',
+  RESUME_GAME = 'ResumeGame',
+  REDIRECT = 'Redirect',
+  READY = 'Ready'
 }
 
 export interface PlayerStateInboundPayload extends PlayerStateOutboundPayload {
@@ -19,6 +22,8 @@ export interface PlayerStateOutboundPayload extends Entity {
   emitting: boolean;
   missile?: Entity;
   damageDealt?: number;
+  /** The team whose enemy planet took the damage */
+  damageTarget?: Team;
   laserShots: {
     remove?: string[];
     add?: NamedEntity[];
@@ -47,11 +52,27 @@ export interface JoinGameAnswerPayload {
   code?: string;
   playerType?: PlayerType;
   spawn?: Position;
+  team?: Team;
+  /** Takes the place in the room back after the server restarted */
+  resumeToken?: string;
+}
+
+/** The room is hosted by another node, which is reached at `url` */
+export interface RedirectPayload {
+  code: string;
+  url: string;
 }
 
 export interface SetMapPayload {
   planets: Planet[];
   enemyPlanet: Planet;
+  /** Only set in race matches, the enemy planet of team BETA */
+  rivalEnemyPlanet?: Planet;
+}
+
+export enum Team {
+  ALPHA = 'ALPHA',
+  BETA = 'BETA'
 }
 
 export interface Planet {
@@ -8,7 +8,8 @@ import {
   PlayerStateInboundPayload,
   PlayerType,
   Position,
-  SetMapPayload
+  SetMapPayload,
+  Team
 } from '../networking/MultiplayerEvent';
 import { assetKeys, bodyLabels, difficulty, events, scenes, tileSize, zoom } from '../utils/constants';
 import { GameMode } from '../session/GameMode';
@@ -414,6 +415,7 @@ export class GameScene extends Phaser.Scene {
         emitting: this.spaceShipEmitterLeft.on,
         dead: this.dead,
         damageDealt: this.damageToCommunicate,
+        damageTarget: this.session.team,
         missile:
           this.missile === undefined
             ? undefined
@@ -510,7 +512,8 @@ export class GameScene extends Phaser.Scene {
       player.clearTint();
     }
 
-    if (payload.damageDealt !== undefined && payload.damageDealt > 0) {
+    const ownTarget = payload.damageTarget === undefined || payload.damageTarget === this.session?.team;
+    if (ownTarget && payload.damageDealt !== undefined && payload.damageDealt > 0) {
       this.reduceEnemyHealth(payload.damageDealt);
     }
 
@@ -589,8 +592,13 @@ export class GameScene extends Phaser.Scene {
   }
 
   public setMap(payload: SetMapPayload) {
+    // in a race each team attacks its own enemy planet, the other one is an obstacle
+    const rivalPlanet = this.session?.team === Team.BETA ? payload.rivalEnemyPlanet : undefined;
+    const target = rivalPlanet !== undefined ? rivalPlanet : payload.enemyPlanet;
+    const other = rivalPlanet !== undefined ? payload.enemyPlanet : payload.rivalEnemyPlanet;
+    const obstacles = other === undefined ? payload.planets : [...payload.planets, other];
     const planets: any[] = [];
-    payload.planets.forEach((planetData) => {
+    obstacles.forEach((planetData) => {
       const key = this.getPlanetImageKeyFromType(planetData.planetType);
       const planet = this.matter.add.image(planetData.position.x, planetData.position.y, key, undefined, {
         label: bodyLabels.planet
@@ -600,20 +608,20 @@ export class GameScene extends Phaser.Scene {
       planets.push(planet);
     });
     const enemyPlanet = this.matter.add.image(
-      payload.enemyPlanet.position.x,
-      payload.enemyPlanet.position.y,
-      this.getPlanetImageKeyFromType(payload.enemyPlanet.planetType),
+      target.position.x,
+      target.position.y,
+      this.getPlanetImageKeyFromType(target.planetType),
       undefined,
       {
         label: bodyLabels.evilPlanet
       }
     );
     this.enemyPlanetCover = this.add.image(
-      payload.enemyPlanet.position.x,
-      payload.enemyPlanet.position.y,
+      target.position.x,
+      target.position.y,
       assetKeys.planets.evil
     );
-    enemyPlanet.setCircle(payload.enemyPlanet.radius);
+    enemyPlanet.setCircle(target.radius);
     enemyPlanet.setStatic(true);
     this.matterCollision.addOnCollideStart({
       objectA: enemyPlanet,
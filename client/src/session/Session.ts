@@ -8,15 +8,21 @@ import {
   PlayerLeftGamePayload,
   PlayerStateInboundPayload,
   PlayerStateOutboundPayload,
+  RedirectPayload,
   RoomLeaderPayload,
   SetMapPayload,
-  SignedGameStatePayload
+  SignedGameStatePayload,
+  Team
 } from '../networking/MultiplayerEvent';
 import { sceneEvents } from '../events/EventCenter';
 import { events } from '../utils/constants';
 
 declare const SERVER_HOST: string;
 
+/** The server keeps the place of a player for two minutes after it restarted */
+const RECONNECT_DELAY = 5000;
+const MAX_RECONNECT_ATTEMPTS = 24;
+
 export class Session {
   private socket?: WebSocket;
   private gameScene?: GameScene;
@@ -26,8 +32,15 @@ export class Session {
   private readonly playerJoinedEvents: PlayerJoinedGamePayload[] = [];
   private readonly playerLeftEvents: PlayerLeftGamePayload[] = [];
   private pingIntervalId?: number;
+  private gameEventsIntervalId?: number;
+  private resumeToken?: string;
+  private reconnectAttempts = 0;
+  private redirectCode?: string;
+  /** Changes when a room is hosted by another node, which is then also reconnected to */
+  private serverUri = Session.defaultServerUri();
   private mapState?: SetMapPayload;
   private gameCode?: string;
+  public team: Team = Team.ALPHA;
   public connected: boolean = false;
 
   constructor() {
@@ -46,13 +59,26 @@ export class Session {
   }
 
   private establishMultiPlayerSession() {
+    this.socket = new WebSocket(this.serverUri);
+    this.setEvents();
+  }
+
+  private static defaultServerUri() {
     const { location } = window;
     const proto = location.protocol.startsWith('https') ? 'wss' : 'ws';
     /* global SERVER_HOST */
-    const wsUri = `${proto}://${SERVER_HOST}/ws/`;
+    return `${proto}://${SERVER_HOST}/ws/`;
+  }
 
-    this.socket = new WebSocket(wsUri);
-    this.setEvents();
+  /** Moves to the node which hosts the room and joins it there */
+  private redirect({ code, url }: RedirectPayload) {
+    if (this.socket !== undefined) {
+      this.socket.onclose = null;
+      this.socket.close();
+    }
+    this.redirectCode = code;
+    this.serverUri = url;
+    this.establishMultiPlayerSession();
   }
 
   private getCurrentPing() {
@@ -76,7 +102,14 @@ export class Session {
       // eslint-disable-next-line no-console
       console.log('Connected to Server');
       this.connected = true;
-      sceneEvents.emit(events.serverConnected);
+      if (this.resumeToken !== undefined) {
+        this.sendEvent(MultiplayerEvent.RESUME_GAME, { code: this.gameCode, token: this.resumeToken });
+      } else if (this.redirectCode !== undefined) {
+        this.connect(this.redirectCode);
+        this.redirectCode = undefined;
+      } else {
+        sceneEvents.emit(events.serverConnected);
+      }
     };
 
     this.socket.onmessage = (ev) => {
@@ -94,10 +127,30 @@ export class Session {
         clearInterval(this.pingIntervalId);
         this.pingIntervalId = undefined;
       }
-      this.gameScene?.disconnectSession();
+      if (this.gameEventsIntervalId !== undefined) {
+        clearInterval(this.gameEventsIntervalId);
+        this.gameEventsIntervalId = undefined;
+      }
+      if (this.resumeToken !== undefined && this.reconnectAttempts < MAX_RECONNECT_ATTEMPTS) {
+        this.reconnectAttempts += 1;
+        setTimeout(() => this.reconnect(), RECONNECT_DELAY);
+      } else {
+        this.gameScene?.disconnectSession();
+      }
     };
   }
 
+  /** Opens a new socket to resume the game, the JoinGame answer then restarts the game scene */
+  private reconnect() {
+    this.gameInitialized = false;
+    this.playerJoinedEvents.length = 0;
+    this.playerLeftEvents.length = 0;
+    this.mapState = undefined;
+    this.isRoomLeader = false;
+    this.secret = undefined;
+    this.establishMultiPlayerSession();
+  }
+
   public initializeGame(gameScene: GameScene) {
     this.gameScene = gameScene;
     this.gameInitialized = true;
@@ -106,8 +159,9 @@ export class Session {
     if (this.mapState !== undefined) {
       gameScene.setMap(this.mapState);
     }
-    setInterval(() => gameScene.sendGameEvents(), 100);
+    this.gameEventsIntervalId = setInterval(() => gameScene.sendGameEvents(), 100);
     this.pingIntervalId = setInterval(this.getCurrentPing.bind(this), 2000);
+    this.sendEvent(MultiplayerEvent.READY, { ready: true });
   }
 
   private sendEvent(event: MultiplayerEvent, payload: any) {
@@ -115,6 +169,9 @@ export class Session {
       console.warn('Tried to send text over an undefined websocket');
       return;
     }
+    if (this.socket.readyState !== WebSocket.OPEN) {
+      return;
+    }
     this.socket.send(`Event ${event}:${JSON.stringify(payload, undefined, 0)}`);
   }
 
@@ -199,10 +256,25 @@ export class Session {
       }
       case MultiplayerEvent.JOIN_GAME: {
         const answer = payload as JoinGameAnswerPayload;
-        this.gameCode = answer.code;
+        if (answer.ok) {
+          this.gameCode = answer.code;
+          this.resumeToken = answer.resumeToken;
+          this.reconnectAttempts = 0;
+        } else if (this.resumeToken !== undefined) {
+          // the place in the room is gone, there is nothing to come back to
+          this.resumeToken = undefined;
+          this.gameScene?.disconnectSession();
+        }
+        if (answer.team !== undefined) {
+          this.team = answer.team;
+        }
         sceneEvents.emit(events.joinGame, answer);
         break;
       }
+      case MultiplayerEvent.REDIRECT: {
+        this.redirect(payload as RedirectPayload);
+        break;
+      }
       case MultiplayerEvent.PING: {
         const state = payload as { timestamp: number };
         sceneEvents.emit(events.updatePing, Date.now().valueOf() - state.timestamp);
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{hmac, pbkdf2};
+use serde::{Deserialize, Serialize};
+
+use crate::token;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+const TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 16;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Account {
+    id: String,
+    username: String,
+    /// `iterations$salt$hash` with salt and hash in base64
+    password_hash: String,
+    created: u64,
+}
+
+/// The account a session authenticated as
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountInfo {
+    pub id: String,
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub account_id: String,
+    pub username: String,
+    pub token: String,
+}
+
+/// Registered accounts, kept in memory and written to a JSON file on every change
+pub struct Accounts {
+    path: Option<PathBuf>,
+    accounts: Mutex<HashMap<String, Account>>,
+    token_key: hmac::Key,
+    rng: SystemRandom,
+    /// Checked against for unknown usernames, so they take as long as a wrong password
+    dummy_hash: String,
+}
+
+impl Accounts {
+    /// Without a `path` the accounts are lost on restart. Without a `token_key`, tokens
+    /// are signed with a random key and stop working on restart.
+    pub fn new(path: Option<PathBuf>, token_key: Option<&[u8]>) -> Result<Self, String> {
+        let rng = SystemRandom::new();
+        let accounts: Vec<Account> = match &path {
+            Some(path) if path.exists() => {
+                let json = fs::read_to_string(path).map_err(|error| error.to_string())?;
+                serde_json::from_str(&json).map_err(|error| error.to_string())?
+            }
+            _ => vec![],
+        };
+        let token_key = match token_key {
+            Some(key) => hmac::Key::new(hmac::HMAC_SHA256, key),
+            None => hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+                .map_err(|_| "failed to generate token key".to_string())?,
+        };
+        let dummy_hash = hash_password(&rng, "")?;
+        Ok(Accounts {
+            path,
+            accounts: Mutex::new(
+                accounts
+                    .into_iter()
+                    .map(|account| (account.username.to_lowercase(), account))
+                    .collect(),
+            ),
+            token_key,
+            rng,
+            dummy_hash,
+        })
+    }
+
+    pub fn register(&self, credentials: &Credentials) -> Result<Session, String> {
+        validate_username(&credentials.username)?;
+        if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(format!(
+                "password must be at least {} characters long",
+                MIN_PASSWORD_LENGTH
+            ));
+        }
+        // hash before taking the lock, it is slow on purpose
+        let password_hash = hash_password(&self.rng, &credentials.password)?;
+        let mut accounts = self.accounts.lock().map_err(|_| "account store poisoned")?;
+        let key = credentials.username.to_lowercase();
+        if accounts.contains_key(&key) {
+            return Err("username is taken".to_string());
+        }
+        let account = Account {
+            id: token::player_id(),
+            username: credentials.username.clone(),
+            password_hash,
+            created: unix_time(),
+        };
+        accounts.insert(key.clone(), account.clone());
+        if let Err(error) = self.save(&accounts) {
+            accounts.remove(&key);
+            return Err(error);
+        }
+        Ok(self.session(&account))
+    }
+
+    pub fn login(&self, credentials: &Credentials) -> Result<Session, String> {
+        let account = self
+            .accounts
+            .lock()
+            .map_err(|_| "account store poisoned")?
+            .get(&credentials.username.to_lowercase())
+            .cloned();
+        match account {
+            Some(account) if verify_password(&account.password_hash, &credentials.password) => {
+                Ok(self.session(&account))
+            }
+            Some(_) => Err("invalid username or password".to_string()),
+            None => {
+                verify_password(&self.dummy_hash, &credentials.password);
+                Err("invalid username or password".to_string())
+            }
+        }
+    }
+
+    /// Checks the signature and expiry of a session token
+    pub fn verify_token(&self, session_token: &str) -> Option<AccountInfo> {
+        let mut parts = session_token.rsplitn(2, '.');
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let claims = parts.next()?;
+        hmac::verify(&self.token_key, claims.as_bytes(), &signature).ok()?;
+        let mut claims = claims.splitn(2, '.');
+        let id = claims.next()?;
+        let expires: u64 = claims.next()?.parse().ok()?;
+        if expires < unix_time() {
+            return None;
+        }
+        self.accounts
+            .lock()
+            .ok()?
+            .values()
+            .find(|account| account.id == id)
+            .map(|account| AccountInfo {
+                id: account.id.clone(),
+                username: account.username.clone(),
+            })
+    }
+
+    fn session(&self, account: &Account) -> Session {
+        let claims = format!("{}.{}", account.id, unix_time() + TOKEN_LIFETIME.as_secs());
+        let signature = hmac::sign(&self.token_key, claims.as_bytes());
+        Session {
+            account_id: account.id.clone(),
+            username: account.username.clone(),
+            token: format!(
+                "{}.{}",
+                claims,
+                base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+            ),
+        }
+    }
+
+    fn save(&self, accounts: &HashMap<String, Account>) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let json = serde_json::to_string_pretty(&accounts.values().collect::<Vec<_>>())
+            .map_err(|error| error.to_string())?;
+        // write next to the store and rename, so a crash never leaves half a file behind
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, json).map_err(|error| error.to_string())?;
+        fs::rename(&temporary, path).map_err(|error| error.to_string())
+    }
+}
+
+fn hash_password(rng: &SystemRandom, password: &str) -> Result<String, String> {
+    let mut salt = [0u8; SALT_LENGTH];
+    rng.fill(&mut salt)
+        .map_err(|_| "failed to generate salt".to_string())?;
+    let mut hash = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations must not be zero"),
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{}${}${}",
+        PBKDF2_ITERATIONS,
+        base64::encode(salt),
+        base64::encode(hash)
+    ))
+}
+
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    let mut parts = password_hash.split('$');
+    let decoded = (|| {
+        let iterations = NonZeroU32::new(parts.next()?.parse().ok()?)?;
+        let salt = base64::decode(parts.next()?).ok()?;
+        let hash = base64::decode(parts.next()?).ok()?;
+        Some((iterations, salt, hash))
+    })();
+    match decoded {
+        Some((iterations, salt, hash)) => pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok(),
+        None => false,
+    }
+}
+
+fn validate_username(username: &str) -> Result<(), String> {
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(format!(
+            "username must be {} to {} characters long",
+            MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("username may only contain letters, digits, '_' and '-'".to_string());
+    }
+    Ok(())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn credentials(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[test]
+    fn registers_and_logs_in() {
+        let accounts = Accounts::new(None, Some(b"test key")).unwrap();
+        let session = accounts
+            .register(&credentials("Pilot", "correct horse"))
+            .unwrap();
+        assert!(accounts
+            .register(&credentials("pilot", "battery staple"))
+            .is_err());
+        assert!(accounts
+            .login(&credentials("pilot", "wrong password"))
+            .is_err());
+        let login = accounts
+            .login(&credentials("PILOT", "correct horse"))
+            .unwrap();
+        assert_eq!(login.account_id, session.account_id);
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let accounts = Accounts::new(None, Some(b"test key")).unwrap();
+        let session = accounts
+            .register(&credentials("Pilot", "correct horse"))
+            .unwrap();
+        let info = accounts.verify_token(&session.token).unwrap();
+        assert_eq!(info.username, "Pilot");
+
+        let other = Accounts::new(None, Some(b"other key")).unwrap();
+        assert_eq!(other.verify_token(&session.token), None);
+        let tampered = session.token.replacen(&session.account_id, "0", 1);
+        assert_eq!(accounts.verify_token(&tampered), None);
+    }
+}
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+
+use crate::server::{MatchOutcome, PlayerType};
+use crate::storage::{MatchRecord, MatchStore};
+
+/// Damage an account has to deal over all its matches for `HEAVYHITTER`
+pub const DAMAGE_GOAL: f64 = 1000.;
+
+/// Ship variants beyond the colours of `PlayerType`, each unlocked by an achievement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Skin {
+    CHROME,
+    GHOST,
+    INFERNO,
+    ROYAL,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Achievement {
+    /// Won a match
+    FIRSTWIN,
+    /// Won a match without dying
+    FLAWLESS,
+    /// Dealt `DAMAGE_GOAL` damage over all recorded matches
+    HEAVYHITTER,
+    /// Won a match in a room with a player of every colour
+    FULLHOUSE,
+}
+
+impl Achievement {
+    pub const ALL: [Achievement; 4] = [
+        Achievement::FIRSTWIN,
+        Achievement::FLAWLESS,
+        Achievement::HEAVYHITTER,
+        Achievement::FULLHOUSE,
+    ];
+
+    pub fn skin(self) -> Skin {
+        match self {
+            Achievement::FIRSTWIN => Skin::CHROME,
+            Achievement::FLAWLESS => Skin::GHOST,
+            Achievement::HEAVYHITTER => Skin::INFERNO,
+            Achievement::FULLHOUSE => Skin::ROYAL,
+        }
+    }
+}
+
+/// What the server observed of one account in a finished match
+#[derive(Debug, Clone)]
+pub struct MatchStats {
+    pub won: bool,
+    pub deaths: u32,
+    /// Damage over all verified matches of the account, this one included
+    pub total_damage: f64,
+    pub room_size: usize,
+    /// Whether the replay of the match backs up what the client reported, deaths and
+    /// damage can not be trusted otherwise
+    pub verified: bool,
+}
+
+/// Every achievement the match qualifies for, whether or not the account already has it
+pub fn earned(stats: &MatchStats) -> Vec<Achievement> {
+    Achievement::ALL
+        .iter()
+        .copied()
+        .filter(|achievement| match achievement {
+            Achievement::FIRSTWIN => stats.won,
+            Achievement::FLAWLESS => stats.verified && stats.won && stats.deaths == 0,
+            Achievement::HEAVYHITTER => stats.verified && stats.total_damage >= DAMAGE_GOAL,
+            Achievement::FULLHOUSE => stats.won && stats.room_size >= PlayerType::ALL.len(),
+        })
+        .collect()
+}
+
+/// Evaluates a recorded match for every player with an account and stores what they
+/// earned. Returns the player ids together with the achievements that are new to them.
+pub fn grant(
+    store: &mut dyn MatchStore,
+    record: &MatchRecord,
+    unlocked_at: u64,
+) -> Result<Vec<(String, Achievement)>, String> {
+    let mut unlocked = vec![];
+    for player in &record.players {
+        let account_id = match &player.account_id {
+            Some(account_id) => account_id,
+            None => continue,
+        };
+        let stats = MatchStats {
+            won: record.outcome == MatchOutcome::WON
+                && match record.winner {
+                    Some(winner) => winner == player.team,
+                    None => true,
+                },
+            deaths: player.deaths,
+            total_damage: store.total_damage(account_id)?,
+            room_size: record.players.len(),
+            verified: player.verified,
+        };
+        for achievement in earned(&stats) {
+            if store.unlock(account_id, achievement, unlocked_at)? {
+                unlocked.push((player.player_id.clone(), achievement));
+            }
+        }
+    }
+    Ok(unlocked)
+}
+
+pub fn owns_skin(achievements: &[Achievement], skin: Skin) -> bool {
+    achievements
+        .iter()
+        .any(|achievement| achievement.skin() == skin)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stats() -> MatchStats {
+        MatchStats {
+            won: true,
+            deaths: 1,
+            total_damage: 50.,
+            room_size: 2,
+            verified: true,
+        }
+    }
+
+    #[test]
+    fn evaluates_match_stats() {
+        assert_eq!(earned(&stats()), vec![Achievement::FIRSTWIN]);
+        assert_eq!(
+            earned(&MatchStats {
+                won: false,
+                deaths: 0,
+                room_size: 10,
+                ..stats()
+            }),
+            vec![]
+        );
+        assert_eq!(
+            earned(&MatchStats {
+                deaths: 0,
+                total_damage: DAMAGE_GOAL,
+                room_size: 10,
+                ..stats()
+            }),
+            Achievement::ALL.to_vec()
+        );
+    }
+
+    #[test]
+    fn needs_a_verified_replay_for_reported_stats() {
+        assert_eq!(
+            earned(&MatchStats {
+                deaths: 0,
+                total_damage: DAMAGE_GOAL,
+                room_size: 10,
+                verified: false,
+                ..stats()
+            }),
+            vec![Achievement::FIRSTWIN, Achievement::FULLHOUSE]
+        );
+    }
+
+    #[test]
+    fn skins_belong_to_their_achievement() {
+        assert!(owns_skin(&[Achievement::FLAWLESS], Skin::GHOST));
+        assert!(!owns_skin(&[Achievement::FLAWLESS], Skin::CHROME));
+        assert!(!owns_skin(&[], Skin::ROYAL));
+    }
+}
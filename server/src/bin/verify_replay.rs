@@ -0,0 +1,46 @@
+//! Re-simulates a recorded match and lists where it diverges, e.g. before a run is
+//! accepted onto a leaderboard:
+//!
+//! `verify-replay replays/ACDEF-1600000000000.jsonl`
+//!
+//! Exits with 1 if the replay diverges and with 2 if it cannot be read.
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+use game_on_2020_server::replay::{verify, Replay};
+
+fn main() {
+    let paths: Vec<PathBuf> = env::args().skip(1).map(PathBuf::from).collect();
+    if paths.is_empty() {
+        eprintln!("usage: verify-replay <replay file>...");
+        process::exit(2);
+    }
+    let mut diverged = false;
+    for path in paths {
+        let replay = match Replay::from_file(&path) {
+            Ok(replay) => replay,
+            Err(error) => {
+                eprintln!("{}: {}", path.display(), error);
+                process::exit(2);
+            }
+        };
+        let divergences = verify(&replay);
+        println!(
+            "{}: room {}, {} frames over {} ms, {} divergences",
+            path.display(),
+            replay.header.code,
+            replay.frames.len(),
+            replay.duration(),
+            divergences.len()
+        );
+        for divergence in &divergences {
+            println!("{}", divergence);
+        }
+        diverged |= !divergences.is_empty();
+    }
+    if diverged {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use log::{info, warn};
+
+use crate::message::{AccountAttempt, CloseConnection, InvalidCode, JoinAttempt, OpenConnection};
+use crate::rate_limit::TokenBucket;
+
+const MAX_CONNECTIONS_PER_IP: usize = 8;
+/// Invalid room codes an address may send before it is banned, one is forgiven per minute
+const MAX_INVALID_CODES: u32 = 5;
+/// Logins and registrations an address may send at once, one more is allowed every 20 seconds
+const MAX_ACCOUNT_ATTEMPTS: u32 = 5;
+const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// Idle addresses are forgotten after this, so their buckets are full again
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+struct Address {
+    connections: usize,
+    join_attempts: TokenBucket,
+    account_attempts: TokenBucket,
+    invalid_codes: TokenBucket,
+    banned_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl Default for Address {
+    fn default() -> Self {
+        Address {
+            connections: 0,
+            join_attempts: TokenBucket::new(10, Duration::from_secs(3)),
+            account_attempts: TokenBucket::new(MAX_ACCOUNT_ATTEMPTS, Duration::from_secs(20)),
+            invalid_codes: TokenBucket::new(MAX_INVALID_CODES, Duration::from_secs(60)),
+            banned_until: None,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+impl Address {
+    fn check_ban(&mut self, now: Instant) -> Result<(), String> {
+        match self.banned_until {
+            Some(until) if until > now => Err("temporarily banned".to_string()),
+            Some(_) => {
+                self.banned_until = None;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Tracks websocket connections and join attempts per remote address
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    addresses: HashMap<String, Address>,
+}
+
+impl ConnectionLimiter {
+    fn open(&mut self, ip: &str) -> Result<(), String> {
+        let now = Instant::now();
+        let address = self.addresses.entry(ip.to_owned()).or_default();
+        address.last_seen = now;
+        address.check_ban(now)?;
+        if address.connections >= MAX_CONNECTIONS_PER_IP {
+            return Err("too many connections".to_string());
+        }
+        address.connections += 1;
+        Ok(())
+    }
+
+    fn close(&mut self, ip: &str) {
+        if let Some(address) = self.addresses.get_mut(ip) {
+            address.connections = address.connections.saturating_sub(1);
+            address.last_seen = Instant::now();
+        }
+    }
+
+    fn attempt_join(&mut self, ip: &str) -> Result<(), String> {
+        let now = Instant::now();
+        let address = self.addresses.entry(ip.to_owned()).or_default();
+        address.last_seen = now;
+        address.check_ban(now)?;
+        if !address.join_attempts.try_take() {
+            return Err("too many attempts".to_string());
+        }
+        Ok(())
+    }
+
+    fn attempt_account(&mut self, ip: &str) -> Result<(), String> {
+        let now = Instant::now();
+        let address = self.addresses.entry(ip.to_owned()).or_default();
+        address.last_seen = now;
+        address.check_ban(now)?;
+        if !address.account_attempts.try_take() {
+            return Err("too many attempts".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns true if the address got banned by this invalid code
+    fn invalid_code(&mut self, ip: &str) -> bool {
+        let now = Instant::now();
+        let address = self.addresses.entry(ip.to_owned()).or_default();
+        address.last_seen = now;
+        if address.check_ban(now).is_ok() && !address.invalid_codes.try_take() {
+            address.banned_until = Some(now + BAN_DURATION);
+            return true;
+        }
+        false
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.addresses.retain(|_, address| {
+            address.connections > 0
+                || address.banned_until > Some(now)
+                || now.duration_since(address.last_seen) < IDLE_TIMEOUT
+        });
+    }
+}
+
+impl Actor for ConnectionLimiter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(PRUNE_INTERVAL, |act, _ctx| act.prune());
+    }
+}
+
+impl Handler<OpenConnection> for ConnectionLimiter {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: OpenConnection, _ctx: &mut Self::Context) -> Self::Result {
+        self.open(&msg.ip).map_err(|reason| {
+            warn!("Refused connection from {}: {}", msg.ip, reason);
+            reason
+        })
+    }
+}
+
+impl Handler<CloseConnection> for ConnectionLimiter {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseConnection, _ctx: &mut Self::Context) {
+        self.close(&msg.ip);
+    }
+}
+
+impl Handler<JoinAttempt> for ConnectionLimiter {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: JoinAttempt, _ctx: &mut Self::Context) -> Self::Result {
+        self.attempt_join(&msg.ip).map_err(|reason| {
+            warn!("Refused join attempt from {}: {}", msg.ip, reason);
+            reason
+        })
+    }
+}
+
+impl Handler<AccountAttempt> for ConnectionLimiter {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: AccountAttempt, _ctx: &mut Self::Context) -> Self::Result {
+        self.attempt_account(&msg.ip).map_err(|reason| {
+            warn!("Refused account attempt from {}: {}", msg.ip, reason);
+            reason
+        })
+    }
+}
+
+impl Handler<InvalidCode> for ConnectionLimiter {
+    type Result = ();
+
+    fn handle(&mut self, msg: InvalidCode, _ctx: &mut Self::Context) {
+        if self.invalid_code(&msg.ip) {
+            warn!(
+                "Banning {} for {:?} after repeated invalid room codes",
+                msg.ip, BAN_DURATION
+            );
+        } else {
+            info!("Invalid room code from {}", msg.ip);
+        }
+    }
+}
+
+impl SystemService for ConnectionLimiter {}
+impl Supervised for ConnectionLimiter {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn caps_connections_per_address() {
+        let mut limiter = ConnectionLimiter::default();
+        for _ in 0..MAX_CONNECTIONS_PER_IP {
+            assert!(limiter.open("1.2.3.4").is_ok());
+        }
+        assert!(limiter.open("1.2.3.4").is_err());
+        assert!(limiter.open("5.6.7.8").is_ok());
+        limiter.close("1.2.3.4");
+        assert!(limiter.open("1.2.3.4").is_ok());
+    }
+
+    #[test]
+    fn limits_account_attempts() {
+        let mut limiter = ConnectionLimiter::default();
+        for _ in 0..MAX_ACCOUNT_ATTEMPTS {
+            assert!(limiter.attempt_account("1.2.3.4").is_ok());
+        }
+        assert!(limiter.attempt_account("1.2.3.4").is_err());
+        assert!(limiter.attempt_join("1.2.3.4").is_ok());
+        assert!(limiter.attempt_account("5.6.7.8").is_ok());
+    }
+
+    #[test]
+    fn bans_after_repeated_invalid_codes() {
+        let mut limiter = ConnectionLimiter::default();
+        for _ in 0..MAX_INVALID_CODES {
+            assert!(!limiter.invalid_code("1.2.3.4"));
+        }
+        assert!(limiter.invalid_code("1.2.3.4"));
+        assert!(limiter.attempt_join("1.2.3.4").is_err());
+        assert!(limiter.open("1.2.3.4").is_err());
+        assert!(limiter.attempt_join("5.6.7.8").is_ok());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+mod file;
+
+pub use file::FileDirectory;
+
+/// A server process which owns rooms
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub id: String,
+    /// Websocket url clients are sent to for the rooms of this node
+    pub url: String,
+}
+
+/// How often a node tells the directory it is still alive
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// A node which has not been heard of for this long is considered dead, its codes free
+pub const NODE_LEASE: Duration = Duration::from_secs(30);
+
+/// Knows which node owns which room code. Every node serving the same players
+/// has to use the same directory.
+pub trait RoomDirectory: Send {
+    /// Makes `node` the owner of `code`, returns false if a live node owns it already
+    fn claim(&self, code: &str, node: &Node) -> Result<bool, String>;
+
+    /// Gives up `code`, unless another node owns it
+    fn release(&self, code: &str, node: &Node) -> Result<(), String>;
+
+    /// Gives up every code of `node`, e.g. those left behind by its previous run
+    fn release_node(&self, node: &Node) -> Result<(), String>;
+
+    /// The live node which owns `code`
+    fn owner(&self, code: &str) -> Result<Option<Node>, String>;
+
+    /// Keeps the codes of `node` owned, has to be called every `HEARTBEAT_INTERVAL`.
+    /// Nodes sharing a process die together, so only directories between processes
+    /// need to know.
+    fn heartbeat(&self, _node: &Node) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A directory for nodes running in the same process
+#[derive(Debug, Clone, Default)]
+pub struct InProcessDirectory {
+    owners: Arc<Mutex<HashMap<String, Node>>>,
+}
+
+impl InProcessDirectory {
+    fn owners(&self) -> Result<MutexGuard<'_, HashMap<String, Node>>, String> {
+        self.owners
+            .lock()
+            .map_err(|_| "room directory is poisoned".to_string())
+    }
+}
+
+impl RoomDirectory for InProcessDirectory {
+    fn claim(&self, code: &str, node: &Node) -> Result<bool, String> {
+        let mut owners = self.owners()?;
+        let owner = owners
+            .entry(code.to_owned())
+            .or_insert_with(|| node.clone());
+        Ok(owner.id == node.id)
+    }
+
+    fn release(&self, code: &str, node: &Node) -> Result<(), String> {
+        let mut owners = self.owners()?;
+        if owners.get(code).map(|owner| owner.id == node.id) == Some(true) {
+            owners.remove(code);
+        }
+        Ok(())
+    }
+
+    fn release_node(&self, node: &Node) -> Result<(), String> {
+        self.owners()?.retain(|_, owner| owner.id != node.id);
+        Ok(())
+    }
+
+    fn owner(&self, code: &str) -> Result<Option<Node>, String> {
+        Ok(self.owners()?.get(code).cloned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(id: &str) -> Node {
+        Node {
+            id: id.to_string(),
+            url: format!("ws://{}/ws/", id),
+        }
+    }
+
+    #[test]
+    fn one_node_owns_each_code() {
+        let directory = InProcessDirectory::default();
+        let other = directory.clone();
+        assert!(directory.claim("ACDEF", &node("a")).unwrap());
+        assert!(directory.claim("ACDEF", &node("a")).unwrap());
+        assert!(!other.claim("ACDEF", &node("b")).unwrap());
+        assert_eq!(other.owner("ACDEF").unwrap(), Some(node("a")));
+
+        other.release("ACDEF", &node("b")).unwrap();
+        assert_eq!(directory.owner("ACDEF").unwrap(), Some(node("a")));
+        directory.release("ACDEF", &node("a")).unwrap();
+        assert!(other.claim("ACDEF", &node("b")).unwrap());
+
+        other.claim("GHJKL", &node("b")).unwrap();
+        directory.release_node(&node("b")).unwrap();
+        assert_eq!(directory.owner("ACDEF").unwrap(), None);
+        assert_eq!(directory.owner("GHJKL").unwrap(), None);
+    }
+}
@@ -0,0 +1,281 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::directory::{Node, RoomDirectory, NODE_LEASE};
+use crate::server::server_time;
+
+const EXTENSION: &str = "json";
+const HEARTBEAT_EXTENSION: &str = "alive";
+const LOCK_EXTENSION: &str = "lock";
+
+/// A directory shared by the nodes of one host. Every owned code is a file naming
+/// its owner, which is created with a hard link so that only one node can claim it.
+/// Next to them every node keeps a file with the time it was last alive at.
+/// Codes of dead nodes are taken over while holding a lock file naming the taker.
+#[derive(Debug, Clone)]
+pub struct FileDirectory {
+    dir: PathBuf,
+    lease: Duration,
+}
+
+impl FileDirectory {
+    pub fn new(dir: PathBuf) -> Result<Self, String> {
+        fs::create_dir_all(&dir).map_err(|error| error.to_string())?;
+        Ok(FileDirectory {
+            dir,
+            lease: NODE_LEASE,
+        })
+    }
+
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Codes end up in file names, so they may only contain letters and digits
+    fn path(&self, code: &str) -> Result<PathBuf, String> {
+        if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err("invalid room code".to_string());
+        }
+        Ok(self.dir.join(format!("{}.{}", code, EXTENSION)))
+    }
+
+    fn heartbeat_path(&self, node: &Node) -> Result<PathBuf, String> {
+        if node.id.is_empty()
+            || !node
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err("invalid node id".to_string());
+        }
+        Ok(self
+            .dir
+            .join(format!("{}.{}", node.id, HEARTBEAT_EXTENSION)))
+    }
+
+    fn is_alive(&self, node: &Node) -> Result<bool, String> {
+        let alive_at: u64 = match fs::read_to_string(self.heartbeat_path(node)?) {
+            Ok(alive_at) => alive_at.trim().parse().unwrap_or_default(),
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error.to_string()),
+        };
+        Ok(server_time().saturating_sub(alive_at) <= self.lease.as_millis() as u64)
+    }
+
+    /// Returns false if the code is owned already
+    fn link(&self, code: &str, node: &Node, path: &Path) -> Result<bool, String> {
+        // the owner is written in full before the link makes it visible
+        let tmp = self.dir.join(format!("{}.{}.tmp", code, node.id));
+        let json = serde_json::to_vec(node).map_err(|error| error.to_string())?;
+        fs::write(&tmp, json).map_err(|error| error.to_string())?;
+        let linked = fs::hard_link(&tmp, path);
+        Self::remove(&tmp)?;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /// Returns `None` if another node is taking over the code right now
+    fn lock(&self, code: &str, node: &Node) -> Result<Option<PathBuf>, String> {
+        let path = self.dir.join(format!("{}.{}", code, LOCK_EXTENSION));
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => return Ok(None),
+            Err(error) => return Err(error.to_string()),
+        };
+        file.write_all(node.id.as_bytes())
+            .map_err(|error| error.to_string())?;
+        Ok(Some(path))
+    }
+
+    /// Has to hold the lock of the code
+    fn take_over(&self, code: &str, node: &Node, path: &Path) -> Result<bool, String> {
+        // the code may have been taken over before the lock was ours
+        match Self::read(path)? {
+            Some(owner) if owner.id == node.id => return Ok(true),
+            Some(owner) if self.is_alive(&owner)? => return Ok(false),
+            _ => Self::remove(path)?,
+        }
+        // a node claiming the code anew may link it before us
+        self.link(code, node, path)?;
+        Ok(Self::read(path)?.map(|owner| owner.id == node.id) == Some(true))
+    }
+
+    fn read(path: &Path) -> Result<Option<Node>, String> {
+        match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|error| format!("invalid room owner: {}", error)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn remove(path: &Path) -> Result<(), String> {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl RoomDirectory for FileDirectory {
+    fn claim(&self, code: &str, node: &Node) -> Result<bool, String> {
+        let path = self.path(code)?;
+        if self.link(code, node, &path)? {
+            return Ok(true);
+        }
+        match Self::read(&path)? {
+            Some(owner) if owner.id == node.id => Ok(true),
+            Some(owner) if self.is_alive(&owner)? => Ok(false),
+            // a dead node can not give up its codes anymore, so they are taken over
+            _ => match self.lock(code, node)? {
+                Some(lock) => {
+                    let taken = self.take_over(code, node, &path);
+                    Self::remove(&lock)?;
+                    taken
+                }
+                None => Ok(false),
+            },
+        }
+    }
+
+    fn release(&self, code: &str, node: &Node) -> Result<(), String> {
+        let path = self.path(code)?;
+        match Self::read(&path)? {
+            Some(owner) if owner.id == node.id => Self::remove(&path),
+            _ => Ok(()),
+        }
+    }
+
+    fn release_node(&self, node: &Node) -> Result<(), String> {
+        for entry in fs::read_dir(&self.dir).map_err(|error| error.to_string())? {
+            let path = entry.map_err(|error| error.to_string())?.path();
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            let owner_id = match extension {
+                Some(EXTENSION) => Self::read(&path).ok().flatten().map(|owner| owner.id),
+                // a node which died while taking over a code leaves its lock behind
+                Some(LOCK_EXTENSION) => fs::read_to_string(&path).ok(),
+                _ => None,
+            };
+            if owner_id.as_deref() == Some(node.id.as_str()) {
+                Self::remove(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn owner(&self, code: &str) -> Result<Option<Node>, String> {
+        match Self::read(&self.path(code)?)? {
+            Some(owner) if self.is_alive(&owner)? => Ok(Some(owner)),
+            _ => Ok(None),
+        }
+    }
+
+    fn heartbeat(&self, node: &Node) -> Result<(), String> {
+        let path = self.heartbeat_path(node)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, server_time().to_string()).map_err(|error| error.to_string())?;
+        fs::rename(&tmp, &path).map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    fn node(id: &str) -> Node {
+        Node {
+            id: id.to_string(),
+            url: format!("ws://{}/ws/", id),
+        }
+    }
+
+    #[test]
+    fn shares_owners_through_the_file_system() {
+        let dir = std::env::temp_dir().join(format!("rooms-{}", crate::token::player_id()));
+        let directory = FileDirectory::new(dir.clone()).unwrap();
+        let other = FileDirectory::new(dir.clone()).unwrap();
+        directory.heartbeat(&node("a")).unwrap();
+        other.heartbeat(&node("b")).unwrap();
+        assert!(directory.claim("ACDEF", &node("a")).unwrap());
+        assert!(directory.claim("ACDEF", &node("a")).unwrap());
+        assert!(!other.claim("ACDEF", &node("b")).unwrap());
+        assert_eq!(other.owner("ACDEF").unwrap(), Some(node("a")));
+        assert!(other.owner("../ACDEF").is_err());
+
+        other.release("ACDEF", &node("b")).unwrap();
+        assert_eq!(directory.owner("ACDEF").unwrap(), Some(node("a")));
+        other.claim("GHJKL", &node("b")).unwrap();
+        directory.release_node(&node("a")).unwrap();
+        assert_eq!(other.owner("ACDEF").unwrap(), None);
+        assert_eq!(other.owner("GHJKL").unwrap(), Some(node("b")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frees_the_codes_of_dead_nodes() {
+        let dir = std::env::temp_dir().join(format!("rooms-{}", crate::token::player_id()));
+        let directory = FileDirectory::new(dir.clone())
+            .unwrap()
+            .with_lease(Duration::from_millis(50));
+        let other = directory.clone();
+        directory.heartbeat(&node("a")).unwrap();
+        assert!(directory.claim("ACDEF", &node("a")).unwrap());
+        assert!(!other.claim("ACDEF", &node("b")).unwrap());
+
+        std::thread::sleep(Duration::from_millis(100));
+        other.heartbeat(&node("b")).unwrap();
+        assert_eq!(other.owner("ACDEF").unwrap(), None);
+        assert!(other.claim("ACDEF", &node("b")).unwrap());
+        assert_eq!(directory.owner("ACDEF").unwrap(), Some(node("b")));
+        assert!(other.heartbeat(&node("../b")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_one_node_takes_over_a_dead_code() {
+        let dir = std::env::temp_dir().join(format!("rooms-{}", crate::token::player_id()));
+        let directory = FileDirectory::new(dir.clone()).unwrap();
+        let codes: Vec<String> = (0..100).map(|index| format!("CODE{}", index)).collect();
+        // without a heartbeat a is dead from the start
+        for code in &codes {
+            assert!(directory.claim(code, &node("a")).unwrap());
+        }
+
+        let barrier = Arc::new(Barrier::new(2));
+        let claimers: Vec<_> = ["b", "c"]
+            .iter()
+            .map(|&id| {
+                let directory = directory.clone();
+                let barrier = barrier.clone();
+                let codes = codes.clone();
+                thread::spawn(move || {
+                    directory.heartbeat(&node(id)).unwrap();
+                    barrier.wait();
+                    codes
+                        .iter()
+                        .map(|code| directory.claim(code, &node(id)).unwrap())
+                        .collect::<Vec<bool>>()
+                })
+            })
+            .collect();
+        let claimed: Vec<Vec<bool>> = claimers
+            .into_iter()
+            .map(|claimer| claimer.join().unwrap())
+            .collect();
+        for (index, code) in codes.iter().enumerate() {
+            assert_ne!(claimed[0][index], claimed[1][index]);
+            let owner = directory.owner(code).unwrap().unwrap();
+            assert_eq!(owner.id == "b", claimed[0][index]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
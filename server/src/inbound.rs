@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+use crate::rate_limit::TokenBucket;
+
+/// Events which are sent many times per second while a match is running
+const STATE_EVENTS: &[&str] = &["Event GameState", "Event PlayerState"];
+/// Keeps the number of buckets bounded when a client makes up event names
+const MAX_TRACKED_EVENTS: usize = 32;
+
+/// Limits on what a single session may send to the server
+#[derive(Debug, Clone, Copy)]
+pub struct InboundLimits {
+    pub max_frame_size: usize,
+    pub max_json_depth: usize,
+    pub state_events_per_second: u32,
+    pub events_per_second: u32,
+}
+
+impl Default for InboundLimits {
+    fn default() -> Self {
+        InboundLimits {
+            max_frame_size: 16 * 1024,
+            max_json_depth: 8,
+            state_events_per_second: 30,
+            events_per_second: 5,
+        }
+    }
+}
+
+impl InboundLimits {
+    pub fn from_env() -> Self {
+        let defaults = InboundLimits::default();
+        let var = |name: &str, default: usize| {
+            env::var(name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("{} must be a number", name))
+                })
+                .unwrap_or(default)
+        };
+        InboundLimits {
+            max_frame_size: var("MAX_FRAME_SIZE", defaults.max_frame_size),
+            max_json_depth: var("MAX_JSON_DEPTH", defaults.max_json_depth),
+            state_events_per_second: var(
+                "STATE_EVENTS_PER_SECOND",
+                defaults.state_events_per_second as usize,
+            ) as u32,
+            events_per_second: var("EVENTS_PER_SECOND", defaults.events_per_second as usize) as u32,
+        }
+    }
+
+    /// Parses an event payload, which has to be a JSON object within the depth limit
+    pub fn parse_payload(&self, payload: &str) -> Result<Map<String, Value>, String> {
+        let json: Value =
+            serde_json::from_str(payload).map_err(|_| "malformed json".to_string())?;
+        if json_depth(&json) > self.max_json_depth {
+            return Err("payload nested too deeply".to_string());
+        }
+        match json {
+            Value::Object(map) => Ok(map),
+            _ => Err("payload is not an object".to_string()),
+        }
+    }
+}
+
+fn json_depth(json: &Value) -> usize {
+    match json {
+        Value::Array(values) => 1 + values.iter().map(json_depth).max().unwrap_or(0),
+        Value::Object(map) => 1 + map.values().map(json_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Rate limits every event type of a session separately
+pub struct EventRateLimiter {
+    limits: InboundLimits,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl EventRateLimiter {
+    pub fn new(limits: InboundLimits) -> Self {
+        EventRateLimiter {
+            limits,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn try_take(&mut self, event: &str) -> bool {
+        let key = if self.buckets.contains_key(event) || self.buckets.len() < MAX_TRACKED_EVENTS {
+            event
+        } else {
+            "other"
+        };
+        let per_second = if STATE_EVENTS.contains(&key) {
+            self.limits.state_events_per_second
+        } else {
+            self.limits.events_per_second
+        }
+        .max(1);
+        self.buckets
+            .entry(key.to_string())
+            .or_insert_with(|| {
+                TokenBucket::new(per_second * 2, Duration::from_secs(1) / per_second)
+            })
+            .try_take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_deep_and_non_object_payloads() {
+        let limits = InboundLimits {
+            max_json_depth: 2,
+            ..InboundLimits::default()
+        };
+        assert!(limits.parse_payload(r#"{"a":{"b":1}}"#).is_ok());
+        assert!(limits.parse_payload(r#"{"a":{"b":[1]}}"#).is_err());
+        assert!(limits.parse_payload("[1]").is_err());
+        assert!(limits.parse_payload("{").is_err());
+    }
+
+    #[test]
+    fn limits_each_event_type_separately() {
+        let mut limiter = EventRateLimiter::new(InboundLimits {
+            events_per_second: 1,
+            ..InboundLimits::default()
+        });
+        assert!(limiter.try_take("Event Chat"));
+        assert!(limiter.try_take("Event Chat"));
+        assert!(!limiter.try_take("Event Chat"));
+        assert!(limiter.try_take("Event Ping"));
+        assert!(limiter.try_take("Event PlayerState"));
+    }
+}
@@ -0,0 +1,13 @@
+pub mod accounts;
+pub mod achievements;
+pub mod connection_limit;
+pub mod directory;
+pub mod inbound;
+pub mod message;
+pub mod rate_limit;
+pub mod replay;
+pub mod room_code;
+pub mod server;
+pub mod session;
+pub mod storage;
+pub mod token;
@@ -1,19 +1,321 @@
 use log::info;
 
 //use actix_files::Files;
+use actix_web::error::BlockingError;
+use actix_web::http::header;
 use actix_web::{middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 
-mod message;
-mod server;
-mod session;
-
+use actix::{Actor, SystemRegistry, SystemService};
 use actix_files::Files;
-use session::PlayerSession;
+use actix_rt::signal::unix::{signal, SignalKind};
+use futures::channel::oneshot;
+use futures::future::{self, FutureExt};
+use game_on_2020_server::accounts::{Accounts, Credentials};
+use game_on_2020_server::connection_limit::ConnectionLimiter;
+use game_on_2020_server::directory::{FileDirectory, InProcessDirectory, Node, RoomDirectory};
+use game_on_2020_server::inbound::InboundLimits;
+use game_on_2020_server::message::{
+    AccountAttempt, CloseConnection, GetLeaderboard, OpenConnection, RecentMatches, RoomCodeStats,
+    Shutdown,
+};
+use game_on_2020_server::replay::{ReplayViewer, Replays};
+use game_on_2020_server::room_code::{
+    default_blocklist, RoomCodes, DEFAULT_ALPHABET, DEFAULT_LENGTH,
+};
+use game_on_2020_server::server::{Snapshot, WordListFilter, WsGameServer};
+use game_on_2020_server::session::PlayerSession;
+use game_on_2020_server::storage::{LeaderboardQuery, SqliteStore};
 use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Heroku's router, which runs the `Procfile`, always sets the forwarding headers.
+/// Anywhere else they could be forged, so they are only trusted when asked for.
+fn trust_proxy_headers() -> bool {
+    env::var("TRUST_PROXY_HEADERS")
+        .map(|trust| trust == "true" || trust == "1")
+        .unwrap_or_else(|_| env::var("DYNO").is_ok())
+}
+
+fn remote_ip(req: &HttpRequest) -> String {
+    // the router appends the address it saw, anything before it came from the client
+    let forwarded = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.rsplit(',').next())
+        .map(|addr| addr.trim().to_string())
+        .filter(|addr| !addr.is_empty());
+    let addr = match forwarded {
+        Some(addr) if trust_proxy_headers() => Some(addr),
+        _ => req.peer_addr().map(|addr| addr.ip().to_string()),
+    };
+    match addr {
+        // a forwarded address may carry a port, which is not part of the identity
+        Some(addr) => match addr.parse::<SocketAddr>() {
+            Ok(socket) => socket.ip().to_string(),
+            Err(_) => addr,
+        },
+        None => "unknown".to_string(),
+    }
+}
+
+const GAME_PROTOCOL: &str = "game-on";
+const REPLAY_PROTOCOL: &str = "game-on-replay";
+const TOKEN_PROTOCOL_PREFIX: &str = "token.";
+const KEY_PROTOCOL_PREFIX: &str = "key.";
 
-async fn game_route(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    ws::start(PlayerSession::default(), &req, stream)
+/// Browsers cannot set headers on a websocket, so secrets are offered as a
+/// `<prefix><secret>` subprotocol next to the real one. A query string would end up in the logs.
+fn protocol_secret<'a>(req: &'a HttpRequest, prefix: &str) -> Option<&'a str> {
+    req.headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|header| header.to_str().ok())?
+        .split(',')
+        .map(str::trim)
+        .find_map(|protocol| protocol.strip_prefix(prefix))
+}
+
+async fn game_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    limits: web::Data<InboundLimits>,
+    accounts: web::Data<Accounts>,
+) -> Result<HttpResponse, Error> {
+    // without a token the player joins as a guest, a bad token is an error though
+    let account = match protocol_secret(&req, TOKEN_PROTOCOL_PREFIX) {
+        Some(token) => match accounts.verify_token(token) {
+            Some(account) => Some(account),
+            None => return Ok(HttpResponse::Unauthorized().body("invalid token")),
+        },
+        None => None,
+    };
+    let ip = remote_ip(&req);
+    let allowed = ConnectionLimiter::from_registry()
+        .send(OpenConnection { ip: ip.clone() })
+        .await
+        .map_err(|_| HttpResponse::InternalServerError())?;
+    if let Err(reason) = allowed {
+        return Ok(HttpResponse::TooManyRequests().body(reason));
+    }
+    let response = ws::start_with_protocols(
+        PlayerSession::new(ip.clone(), *limits.get_ref(), account),
+        &[GAME_PROTOCOL],
+        &req,
+        stream,
+    );
+    if response.is_err() {
+        ConnectionLimiter::from_registry().do_send(CloseConnection { ip });
+    }
+    response
+}
+
+async fn replay_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    id: web::Path<String>,
+    replays: web::Data<Replays>,
+) -> Result<HttpResponse, Error> {
+    if !replays.may_view(protocol_secret(&req, KEY_PROTOCOL_PREFIX)) {
+        return Ok(HttpResponse::Unauthorized().body("invalid replay key"));
+    }
+    let replay = match web::block(move || replays.load(&id)).await {
+        Ok(replay) => replay,
+        Err(BlockingError::Error(reason)) => return Ok(HttpResponse::NotFound().body(reason)),
+        Err(BlockingError::Canceled) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    let ip = remote_ip(&req);
+    let allowed = ConnectionLimiter::from_registry()
+        .send(OpenConnection { ip: ip.clone() })
+        .await
+        .map_err(|_| HttpResponse::InternalServerError())?;
+    if let Err(reason) = allowed {
+        return Ok(HttpResponse::TooManyRequests().body(reason));
+    }
+    let response = ws::start_with_protocols(
+        ReplayViewer::new(ip.clone(), replay),
+        &[REPLAY_PROTOCOL],
+        &req,
+        stream,
+    );
+    if response.is_err() {
+        ConnectionLimiter::from_registry().do_send(CloseConnection { ip });
+    }
+    response
+}
+
+async fn list_replays(
+    req: HttpRequest,
+    replays: web::Data<Replays>,
+) -> Result<HttpResponse, Error> {
+    let key = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+    if !replays.may_view(key) {
+        return Ok(HttpResponse::Unauthorized().body("invalid replay key"));
+    }
+    Ok(match web::block(move || replays.list()).await {
+        Ok(ids) => HttpResponse::Ok().json(ids),
+        Err(BlockingError::Error(error)) => HttpResponse::InternalServerError().body(error),
+        Err(BlockingError::Canceled) => HttpResponse::InternalServerError().finish(),
+    })
+}
+
+/// Every login and registration hashes a password, so they are limited per address
+async fn attempt_account(req: &HttpRequest) -> Result<Option<HttpResponse>, Error> {
+    let allowed = ConnectionLimiter::from_registry()
+        .send(AccountAttempt { ip: remote_ip(req) })
+        .await
+        .map_err(|_| HttpResponse::InternalServerError())?;
+    Ok(allowed
+        .err()
+        .map(|reason| HttpResponse::TooManyRequests().body(reason)))
+}
+
+async fn register(
+    req: HttpRequest,
+    credentials: web::Json<Credentials>,
+    accounts: web::Data<Accounts>,
+) -> Result<HttpResponse, Error> {
+    if let Some(refused) = attempt_account(&req).await? {
+        return Ok(refused);
+    }
+    let session = web::block(move || accounts.register(&credentials)).await;
+    Ok(match session {
+        Ok(session) => {
+            info!("Registered account {}", session.username);
+            HttpResponse::Created().json(session)
+        }
+        Err(BlockingError::Error(reason)) => HttpResponse::BadRequest().body(reason),
+        Err(BlockingError::Canceled) => HttpResponse::InternalServerError().finish(),
+    })
+}
+
+async fn login(
+    req: HttpRequest,
+    credentials: web::Json<Credentials>,
+    accounts: web::Data<Accounts>,
+) -> Result<HttpResponse, Error> {
+    if let Some(refused) = attempt_account(&req).await? {
+        return Ok(refused);
+    }
+    let session = web::block(move || accounts.login(&credentials)).await;
+    Ok(match session {
+        Ok(session) => HttpResponse::Ok().json(session),
+        Err(BlockingError::Error(reason)) => HttpResponse::Unauthorized().body(reason),
+        Err(BlockingError::Canceled) => HttpResponse::InternalServerError().finish(),
+    })
+}
+
+async fn recent_matches() -> Result<HttpResponse, Error> {
+    let matches = WsGameServer::from_registry()
+        .send(RecentMatches { limit: 20 })
+        .await
+        .map_err(|_| HttpResponse::InternalServerError())?;
+    Ok(match matches {
+        Ok(matches) => HttpResponse::Ok().json(matches),
+        Err(error) => HttpResponse::InternalServerError().body(error),
+    })
+}
+
+async fn leaderboard(query: web::Query<LeaderboardQuery>) -> Result<HttpResponse, Error> {
+    let entries = WsGameServer::from_registry()
+        .send(GetLeaderboard {
+            query: query.into_inner(),
+        })
+        .await
+        .map_err(|_| HttpResponse::InternalServerError())?;
+    Ok(match entries {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(error) => HttpResponse::InternalServerError().body(error),
+    })
+}
+
+async fn room_code_metrics() -> Result<HttpResponse, Error> {
+    let metrics = WsGameServer::from_registry()
+        .send(RoomCodeStats)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError())?;
+    Ok(HttpResponse::Ok().json(metrics))
+}
+
+fn room_codes_from_env() -> RoomCodes {
+    let length = env::var("ROOM_CODE_LENGTH")
+        .map(|length| length.parse().expect("ROOM_CODE_LENGTH must be a number"))
+        .unwrap_or(DEFAULT_LENGTH);
+    let alphabet = env::var("ROOM_CODE_ALPHABET").unwrap_or_else(|_| DEFAULT_ALPHABET.to_string());
+    let blocklist = match env::var("ROOM_CODE_BLOCKLIST") {
+        Ok(path) => fs::read_to_string(&path)
+            .expect("Failed to read room code blocklist")
+            .lines()
+            .map(String::from)
+            .collect(),
+        Err(_) => default_blocklist(),
+    };
+    RoomCodes::new(length, &alphabet, blocklist).expect("Invalid room code configuration")
+}
+
+/// Heroku sends SIGTERM on every deploy and kills the process 30 seconds later
+fn shutdown_grace_from_env() -> Duration {
+    Duration::from_secs(
+        env::var("SHUTDOWN_GRACE")
+            .map(|grace| grace.parse().expect("SHUTDOWN_GRACE must be a number"))
+            .unwrap_or(25),
+    )
+}
+
+/// Waits for SIGTERM or Ctrl-C, lets the game server drain its rooms and then stops
+/// accepting connections
+async fn shutdown_on_signal(srv: actix_web::dev::Server, grace: Duration) {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    future::select(
+        Box::pin(terminate.recv()),
+        Box::pin(actix_rt::signal::ctrl_c().map(|_| ())),
+    )
+    .await;
+    let (drained, on_drained) = oneshot::channel();
+    WsGameServer::from_registry().do_send(Shutdown { grace, drained });
+    let _ = on_drained.await;
+    info!("All sessions closed, stopping server");
+    srv.stop(true).await;
+}
+
+/// Rooms are spread over `ROOM_THREADS` threads, by default one per core
+fn room_threads_from_env() -> usize {
+    env::var("ROOM_THREADS")
+        .map(|threads| threads.parse().expect("ROOM_THREADS must be a number"))
+        .unwrap_or_else(|_| num_cpus::get())
+}
+
+/// Several nodes on one host share a `ROOM_DIRECTORY`, each with its own `NODE_ID`
+/// and the `NODE_URL` players reach its rooms at
+fn room_directory_from_env(port: u16) -> (Node, Box<dyn RoomDirectory>) {
+    let id = env::var("NODE_ID").unwrap_or_else(|_| port.to_string());
+    match env::var("ROOM_DIRECTORY") {
+        Ok(path) => {
+            // players are sent there from other nodes, a guess would only work on this host
+            let node = Node {
+                id,
+                url: env::var("NODE_URL").expect("NODE_URL must be set with ROOM_DIRECTORY"),
+            };
+            info!("Sharing rooms as node {} through {}", node.id, path);
+            let directory =
+                FileDirectory::new(PathBuf::from(path)).expect("Failed to open room directory");
+            (node, Box::new(directory))
+        }
+        Err(_) => {
+            let node = Node {
+                id,
+                url: format!("ws://localhost:{}/ws/", port),
+            };
+            (node, Box::new(InProcessDirectory::default()))
+        }
+    }
 }
 
 #[actix_rt::main]
@@ -25,10 +327,80 @@ async fn main() -> std::io::Result<()> {
         .parse()
         .expect("PORT must be a number");
 
-    let srv = HttpServer::new(|| {
+    let database = env::var("DATABASE_FILE").unwrap_or_else(|_| "matches.sqlite".to_string());
+    let store = SqliteStore::open(&database).expect("Failed to open the match database");
+    info!("Recording matches in {}", database);
+    let replay_dir = env::var("REPLAY_DIR").unwrap_or_else(|_| "replays".to_string());
+    let mut replays = Replays::new(PathBuf::from(&replay_dir))
+        .expect("Failed to create the replay directory")
+        .with_retention(
+            env::var("REPLAY_RETENTION")
+                .map(|count| count.parse().expect("REPLAY_RETENTION must be a number"))
+                .unwrap_or(500),
+        );
+    info!("Recording replays to {}", replay_dir);
+    match env::var("REPLAY_KEY") {
+        Ok(key) if !key.is_empty() => replays = replays.with_viewer_key(key),
+        _ => info!("REPLAY_KEY is not set, nobody can watch replays"),
+    }
+    let mut server = WsGameServer::default()
+        .with_room_codes(room_codes_from_env())
+        .with_room_threads(room_threads_from_env())
+        .with_store(Box::new(store))
+        .with_replays(replays.clone());
+    let snapshot_file =
+        PathBuf::from(env::var("SNAPSHOT_FILE").unwrap_or_else(|_| "rooms.json".to_string()));
+    match Snapshot::take(&snapshot_file) {
+        Ok(Some(snapshot)) => server = server.with_snapshot(snapshot),
+        Ok(None) => (),
+        Err(error) => info!(
+            "Not restoring rooms from {}: {}",
+            snapshot_file.display(),
+            error
+        ),
+    }
+    server = server.with_snapshot_file(snapshot_file);
+    let (node, directory) = room_directory_from_env(port);
+    server = server.with_directory(node, directory);
+    if let Ok(path) = env::var("CHAT_BLOCKLIST") {
+        let filter = WordListFilter::from_file(&path).expect("Failed to read chat blocklist");
+        info!("Filtering chat with the blocklist {}", path);
+        server = server.with_chat_filter(Box::new(filter));
+    }
+    SystemRegistry::set(server.start());
+
+    let accounts = web::Data::new(
+        Accounts::new(
+            Some(PathBuf::from(
+                env::var("ACCOUNTS_FILE").unwrap_or_else(|_| "accounts.json".to_string()),
+            )),
+            env::var("ACCOUNT_TOKEN_KEY")
+                .ok()
+                .as_deref()
+                .map(str::as_bytes),
+        )
+        .expect("Failed to load accounts"),
+    );
+    if env::var("ACCOUNT_TOKEN_KEY").is_err() {
+        info!("ACCOUNT_TOKEN_KEY is not set, account tokens will not survive a restart");
+    }
+
+    let replays = web::Data::new(replays);
+    let limits = InboundLimits::from_env();
+    let srv = HttpServer::new(move || {
         App::new()
+            .data(limits)
+            .app_data(accounts.clone())
+            .app_data(replays.clone())
             .wrap(middleware::Logger::default())
             .service(web::resource("/ws/").to(game_route))
+            .service(web::resource("/accounts/register").route(web::post().to(register)))
+            .service(web::resource("/accounts/login").route(web::post().to(login)))
+            .service(web::resource("/matches").to(recent_matches))
+            .service(web::resource("/leaderboards").to(leaderboard))
+            .service(web::resource("/metrics/room-codes").to(room_code_metrics))
+            .service(web::resource("/replays").to(list_replays))
+            .service(web::resource("/replays/{id}").to(replay_route))
             .service(Files::new("/", "./static/").index_file("index.html"))
     })
     .bind(("0.0.0.0", port))
@@ -36,5 +408,7 @@ async fn main() -> std::io::Result<()> {
 
     info!("Starting server: 0.0.0.0:{}", port);
 
-    srv.run().await
+    let srv = srv.disable_signals().shutdown_timeout(5).run();
+    actix_rt::spawn(shutdown_on_signal(srv.clone(), shutdown_grace_from_env()));
+    srv.await
 }
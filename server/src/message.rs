@@ -1,20 +1,67 @@
 use actix::prelude::*;
+use futures::channel::oneshot;
+use std::time::Duration;
+
+use crate::achievements::{Achievement, Skin};
+use crate::room_code::RoomCodeMetrics;
+use crate::server::{
+    Coordinates, Emote, GameMode, GameRoom, MarkerKind, MatchPhase, PlayerType, RoomSnapshot, Team,
+};
+use crate::storage::{LeaderboardEntry, LeaderboardQuery, MatchRecord};
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub struct Message(pub String);
 
+/// Tells a session to close its connection
 #[derive(Clone, Message)]
-#[rtype(result = "Result<String, String>")]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub reason: String,
+}
+
+/// The room a player got into, everything the player sends next goes straight to it
+pub struct Joined {
+    pub player_id: String,
+    pub game_name: String,
+    pub room: Addr<GameRoom>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Joined, String>")]
 pub struct JoinGame {
     pub game_name: String,
     pub player: Recipient<Message>,
+    pub disconnect: Recipient<Disconnect>,
+    pub spectator: bool,
+    pub name: Option<String>,
+    /// Used as display name if no name was requested and nobody in the room took it
+    pub account_name: Option<String>,
+    pub player_type: Option<PlayerType>,
+    pub account_id: Option<String>,
 }
 
+/// Takes the place of a player restored from a snapshot
 #[derive(Clone, Message)]
-#[rtype(result = "Result<(String, String),String>")]
+#[rtype(result = "Result<Joined, String>")]
+pub struct ResumeGame {
+    pub game_name: String,
+    pub resume_token: String,
+    pub player: Recipient<Message>,
+    pub disconnect: Recipient<Disconnect>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Joined, String>")]
 pub struct CreateGame {
     pub player: Recipient<Message>,
+    pub disconnect: Recipient<Disconnect>,
+    pub name: Option<String>,
+    pub account_name: Option<String>,
+    pub player_type: Option<PlayerType>,
+    pub account_id: Option<String>,
+    pub mode: GameMode,
+    pub duration: Option<Duration>,
 }
 
 #[derive(Clone, Message)]
@@ -29,22 +76,262 @@ pub struct LeaveGame {
 pub struct GameState {
     pub game_name: String,
     pub sender_id: String,
-    pub secret: String,
+    pub secret: Option<String>,
     pub payload: serde_json::Value,
 }
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub struct StartGame {
-    pub secret: String,
+    pub secret: Option<String>,
+    pub sender_id: String,
+    pub game_name: String,
+    pub force: bool,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SetReady {
+    pub sender_id: String,
+    pub game_name: String,
+    pub ready: bool,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ChangeColor {
+    pub sender_id: String,
+    pub game_name: String,
+    pub player_type: PlayerType,
+}
+
+/// Only players with an account can pick a skin, `None` goes back to the plain colour
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ChangeSkin {
+    pub sender_id: String,
+    pub game_name: String,
+    pub skin: Option<Skin>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Rematch {
+    pub secret: Option<String>,
+    pub sender_id: String,
+    pub game_name: String,
+    pub same_seed: bool,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SetTeam {
+    pub secret: Option<String>,
     pub sender_id: String,
     pub game_name: String,
+    pub player_id: String,
+    pub team: Team,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct KickPlayer {
+    pub secret: Option<String>,
+    pub sender_id: String,
+    pub game_name: String,
+    pub player_id: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct TransferLeadership {
+    pub secret: Option<String>,
+    pub sender_id: String,
+    pub game_name: String,
+    pub player_id: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct LockRoom {
+    pub secret: Option<String>,
+    pub sender_id: String,
+    pub game_name: String,
+    pub locked: bool,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SetMaxPlayers {
+    pub secret: Option<String>,
+    pub sender_id: String,
+    pub game_name: String,
+    pub max_players: usize,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Chat {
+    pub game_name: String,
+    pub sender_id: String,
+    pub message: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct PlaceMarker {
+    pub game_name: String,
+    pub sender_id: String,
+    pub kind: MarkerKind,
+    pub position: Coordinates,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SendEmote {
+    pub game_name: String,
+    pub sender_id: String,
+    pub emote: Emote,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct MutePlayer {
+    pub secret: Option<String>,
+    pub sender_id: String,
+    pub game_name: String,
+    pub player_id: String,
+    pub muted: bool,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ReportLatency {
+    pub game_name: String,
+    pub sender_id: String,
+    pub latency: Duration,
+}
+
+/// The state a player reports every 100 ms, relayed to the room with the damage the
+/// server counted in place of the reported one
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct PlayerState {
+    pub game_name: String,
+    pub sender_id: String,
+    pub state: serde_json::Map<String, serde_json::Value>,
+    pub damage: f64,
+    /// The team whose enemy planet was hit, the own team if not given
+    pub target: Option<Team>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct PlayerDied {
+    pub game_name: String,
+    pub sender_id: String,
 }
 
 #[derive(Clone, Message)]
 #[rtype(result = "Vec<String>")]
 pub struct ListGames;
 
+/// Sent by a room to the server whenever its match changes phase
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct RoomPhase {
+    pub code: String,
+    pub phase: MatchPhase,
+}
+
+/// Sent by a room to the server once it stopped
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct RoomClosed {
+    pub code: String,
+}
+
+/// Stops a room, it answers with its snapshot unless nobody is left in it
+#[derive(Clone, Message)]
+#[rtype(result = "Option<RoomSnapshot>")]
+pub struct CloseRoom;
+
+/// Stops new games and gives running matches `grace` to end, `drained` fires once
+/// every session was told to close
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub grace: Duration,
+    pub drained: oneshot::Sender<()>,
+}
+
+/// Broadcast to every session while the server shuts down
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ServerShutdown {
+    /// Server time in ms at which the remaining sessions are closed
+    pub deadline: u64,
+}
+
+/// Broadcast to every session when the server is about to stop
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct CloseSessions;
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct OpenConnection {
+    pub ip: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct CloseConnection {
+    pub ip: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct JoinAttempt {
+    pub ip: String,
+}
+
+/// A login or registration, which hashes a password and is expensive on purpose
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct AccountAttempt {
+    pub ip: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct InvalidCode {
+    pub ip: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "RoomCodeMetrics")]
+pub struct RoomCodeStats;
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Vec<MatchRecord>, String>")]
+pub struct RecentMatches {
+    pub limit: usize,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Vec<LeaderboardEntry>, String>")]
+pub struct GetLeaderboard {
+    pub query: LeaderboardQuery,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Vec<Achievement>, String>")]
+pub struct GetAchievements {
+    pub account_id: String,
+}
+
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub struct GameMessage {
@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+
+/// Allows bursts of up to `capacity` actions and refills one action per `refill` interval
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill: Duration,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill: Duration) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    /// Takes as many of `count` actions as the bucket allows and returns how many that were
+    pub fn take_up_to(&mut self, count: u32) -> u32 {
+        self.take_up_to_at(count, Instant::now())
+    }
+
+    fn take_up_to_at(&mut self, count: u32, now: Instant) -> u32 {
+        let mut taken = 0;
+        while taken < count && self.try_take_at(now) {
+            taken += 1;
+        }
+        taken
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() / self.refill.as_secs_f64()).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_up_to_capacity() {
+        let mut bucket = TokenBucket::new(3, Duration::from_secs(1));
+        let now = bucket.last_refill;
+        assert!(bucket.try_take_at(now));
+        assert!(bucket.try_take_at(now));
+        assert!(bucket.try_take_at(now));
+        assert!(!bucket.try_take_at(now), "Bucket should be empty");
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(1));
+        let now = bucket.last_refill;
+        assert!(bucket.try_take_at(now));
+        assert!(bucket.try_take_at(now));
+        assert!(!bucket.try_take_at(now + Duration::from_millis(500)));
+        assert!(bucket.try_take_at(now + Duration::from_millis(1100)));
+        assert!(!bucket.try_take_at(now + Duration::from_millis(1200)));
+    }
+
+    #[test]
+    fn takes_what_is_left() {
+        let mut bucket = TokenBucket::new(4, Duration::from_millis(40));
+        let now = bucket.last_refill;
+        assert_eq!(bucket.take_up_to_at(u32::MAX, now), 4);
+        assert_eq!(bucket.take_up_to_at(2, now + Duration::from_millis(100)), 2);
+        assert_eq!(bucket.take_up_to_at(0, now + Duration::from_secs(1)), 0);
+    }
+
+    #[test]
+    fn does_not_refill_above_capacity() {
+        let mut bucket = TokenBucket::new(1, Duration::from_secs(1));
+        let now = bucket.last_refill + Duration::from_secs(60);
+        assert!(bucket.try_take_at(now));
+        assert!(!bucket.try_take_at(now));
+    }
+}
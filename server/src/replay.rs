@@ -0,0 +1,386 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use ring::constant_time;
+use serde::{Deserialize, Serialize};
+
+mod verify;
+mod viewer;
+
+pub use verify::{verify, Divergence, DivergenceKind};
+pub use viewer::ReplayViewer;
+
+/// Bumped whenever the layout of a replay file changes
+pub const FORMAT_VERSION: u32 = 1;
+const EXTENSION: &str = "jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    /// Sent by a player and relayed to the room
+    IN,
+    /// Sent by the server to the whole room
+    OUT,
+}
+
+/// The first line of a replay file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub code: String,
+    /// Server time in ms at which the recording started
+    pub started_at: u64,
+}
+
+/// Every other line of a replay file, keys are short as there are thousands of them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    /// Milliseconds since the recording started
+    pub t: u64,
+    pub d: Direction,
+    /// Sender of an inbound frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<String>,
+    pub m: String,
+}
+
+impl Frame {
+    /// The event name of the message, e.g. `Event PlayerState`
+    pub fn event(&self) -> &str {
+        self.m.split(':').next().unwrap_or_default()
+    }
+
+    /// The damage a player state deals to the enemy planet
+    pub fn damage(&self) -> f64 {
+        self.m
+            .split_once(':')
+            .and_then(|(_, payload)| serde_json::from_str::<serde_json::Value>(payload).ok())
+            .and_then(|state| state.get("damageDealt").and_then(|damage| damage.as_f64()))
+            .unwrap_or(0.)
+    }
+}
+
+/// Writes the frames of one match to a replay file as they happen
+#[derive(Debug)]
+pub struct Recorder {
+    id: String,
+    writer: BufWriter<File>,
+    started_at: u64,
+}
+
+impl Recorder {
+    pub fn record(
+        &mut self,
+        at: u64,
+        direction: Direction,
+        player_id: Option<&str>,
+        message: &str,
+    ) -> Result<(), String> {
+        let frame = Frame {
+            t: at.saturating_sub(self.started_at),
+            d: direction,
+            p: player_id.map(str::to_string),
+            m: message.to_owned(),
+        };
+        write_line(&mut self.writer, &frame)
+    }
+
+    /// Returns the id the replay can be loaded with
+    pub fn finish(mut self) -> Result<String, String> {
+        self.writer.flush().map_err(|error| error.to_string())?;
+        Ok(self.id)
+    }
+}
+
+fn write_line<T: Serialize>(writer: &mut BufWriter<File>, value: &T) -> Result<(), String> {
+    serde_json::to_writer(&mut *writer, value).map_err(|error| error.to_string())?;
+    writer.write_all(b"\n").map_err(|error| error.to_string())
+}
+
+/// A recorded match loaded back into memory
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub frames: Vec<Frame>,
+}
+
+impl Replay {
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|_| "replay not found".to_string())?;
+        let mut lines = BufReader::new(file).lines();
+        let header: ReplayHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line.map_err(|error| error.to_string())?)
+                .map_err(|error| format!("invalid replay header: {}", error))?,
+            None => return Err("replay is empty".to_string()),
+        };
+        if header.version != FORMAT_VERSION {
+            return Err(format!("unsupported replay version {}", header.version));
+        }
+        let frames = lines
+            .map(|line| {
+                let line = line.map_err(|error| error.to_string())?;
+                serde_json::from_str(&line).map_err(|error| format!("invalid frame: {}", error))
+            })
+            .collect::<Result<Vec<Frame>, String>>()?;
+        Ok(Replay { header, frames })
+    }
+
+    pub fn duration(&self) -> u64 {
+        self.frames.last().map(|frame| frame.t).unwrap_or(0)
+    }
+
+    /// Index of the first frame at or after `t`
+    pub fn position(&self, t: u64) -> usize {
+        self.frames.partition_point(|frame| frame.t < t)
+    }
+
+    /// The frames a client needs to catch up to the frame at `position`: every
+    /// event except the game states and player states superseded by later ones.
+    /// Player states that deal damage are kept, the client sums their damage up.
+    pub fn catch_up(&self, position: usize) -> Vec<&Frame> {
+        let mut latest = HashSet::new();
+        let mut frames: Vec<&Frame> = self.frames[..position]
+            .iter()
+            .rev()
+            .filter(|frame| match frame.event() {
+                "Event GameState" | "Event PlayerState" => {
+                    latest.insert((frame.event(), frame.p.as_deref())) || frame.damage() > 0.
+                }
+                _ => true,
+            })
+            .collect();
+        frames.reverse();
+        frames
+    }
+}
+
+/// The directory replays are written to and served from
+#[derive(Debug, Clone)]
+pub struct Replays {
+    dir: PathBuf,
+    /// Replays older than the newest this many are deleted
+    retention: Option<usize>,
+    /// Replays hold the names of players, they are only served to who knows the key
+    viewer_key: Option<String>,
+}
+
+impl Replays {
+    pub fn new(dir: PathBuf) -> Result<Self, String> {
+        fs::create_dir_all(&dir).map_err(|error| error.to_string())?;
+        Ok(Replays {
+            dir,
+            retention: None,
+            viewer_key: None,
+        })
+    }
+
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    pub fn with_viewer_key(mut self, viewer_key: String) -> Self {
+        self.viewer_key = Some(viewer_key);
+        self
+    }
+
+    /// Without a viewer key nobody may list or watch replays
+    pub fn may_view(&self, key: Option<&str>) -> bool {
+        match (&self.viewer_key, key) {
+            (Some(viewer_key), Some(key)) => {
+                constant_time::verify_slices_are_equal(viewer_key.as_bytes(), key.as_bytes())
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Starts the replay `<code>-<started_at>`
+    pub fn record(&self, code: &str, started_at: u64) -> Result<Recorder, String> {
+        let id = format!("{}-{}", code, started_at);
+        let file = File::create(self.path(&id)?).map_err(|error| error.to_string())?;
+        let mut writer = BufWriter::new(file);
+        write_line(
+            &mut writer,
+            &ReplayHeader {
+                version: FORMAT_VERSION,
+                code: code.to_owned(),
+                started_at,
+            },
+        )?;
+        self.prune();
+        Ok(Recorder {
+            id,
+            writer,
+            started_at,
+        })
+    }
+
+    /// Ids of all replays, newest first
+    pub fn list(&self) -> Result<Vec<String>, String> {
+        let mut replays: Vec<(u64, String)> = fs::read_dir(&self.dir)
+            .map_err(|error| error.to_string())?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != EXTENSION {
+                    return None;
+                }
+                let id = path.file_stem()?.to_str()?.to_string();
+                let started_at = id.rsplit('-').next()?.parse().ok()?;
+                Some((started_at, id))
+            })
+            .collect();
+        replays.sort_by(|a, b| b.cmp(a));
+        Ok(replays.into_iter().map(|(_, id)| id).collect())
+    }
+
+    /// Deletes the replays beyond the retention, a failure only leaves them for next time
+    fn prune(&self) {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return,
+        };
+        let ids = match self.list() {
+            Ok(ids) => ids,
+            Err(error) => {
+                warn!("Failed to list replays for pruning: {}", error);
+                return;
+            }
+        };
+        for id in ids.iter().skip(retention) {
+            if let Err(error) = self
+                .path(id)
+                .and_then(|path| fs::remove_file(path).map_err(|error| error.to_string()))
+            {
+                warn!("Failed to delete replay {}: {}", id, error);
+            }
+        }
+    }
+
+    pub fn load(&self, id: &str) -> Result<Replay, String> {
+        Replay::from_file(&self.path(id)?)
+    }
+
+    /// Ids end up in file names, so they may not contain anything but room code
+    /// characters, digits and the separator
+    fn path(&self, id: &str) -> Result<PathBuf, String> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err("invalid replay id".to_string());
+        }
+        Ok(self.dir.join(format!("{}.{}", id, EXTENSION)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn replays() -> Replays {
+        let dir = std::env::temp_dir().join(format!("replays-{}", crate::token::player_id()));
+        Replays::new(dir).unwrap()
+    }
+
+    #[test]
+    fn records_and_loads_replays() {
+        let replays = replays();
+        let mut recorder = replays.record("ACDEF", 1000).unwrap();
+        recorder
+            .record(1000, Direction::OUT, None, "Event SetMap:{}")
+            .unwrap();
+        recorder
+            .record(1250, Direction::IN, Some("a"), "Event PlayerState:{}")
+            .unwrap();
+        assert_eq!(recorder.finish().unwrap(), "ACDEF-1000");
+        replays.record("ACDEF", 2000).unwrap().finish().unwrap();
+
+        assert_eq!(replays.list().unwrap(), vec!["ACDEF-2000", "ACDEF-1000"]);
+        let replay = replays.load("ACDEF-1000").unwrap();
+        assert_eq!(replay.header.code, "ACDEF");
+        assert_eq!(replay.duration(), 250);
+        assert_eq!(replay.frames[1].p.as_deref(), Some("a"));
+        assert!(replays.load("../ACDEF-1000").is_err());
+        fs::remove_dir_all(&replays.dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_newest_replays() {
+        let replays = replays().with_retention(2);
+        for started_at in 1..=3 {
+            replays
+                .record("ACDEF", started_at)
+                .unwrap()
+                .finish()
+                .unwrap();
+        }
+        assert_eq!(replays.list().unwrap(), vec!["ACDEF-3", "ACDEF-2"]);
+        fs::remove_dir_all(&replays.dir).unwrap();
+    }
+
+    #[test]
+    fn needs_the_viewer_key() {
+        let replays = replays();
+        assert!(!replays.may_view(None));
+        assert!(!replays.may_view(Some("")));
+        let replays = replays.with_viewer_key("secret".to_string());
+        assert!(replays.may_view(Some("secret")));
+        assert!(!replays.may_view(Some("guess")));
+        assert!(!replays.may_view(None));
+        fs::remove_dir_all(&replays.dir).unwrap();
+    }
+
+    #[test]
+    fn catches_up_with_latest_states_and_all_damage() {
+        let frame = |t, p: Option<&str>, m: &str| Frame {
+            t,
+            d: Direction::IN,
+            p: p.map(str::to_string),
+            m: m.to_string(),
+        };
+        let replay = Replay {
+            header: ReplayHeader {
+                version: FORMAT_VERSION,
+                code: "ACDEF".to_string(),
+                started_at: 0,
+            },
+            frames: vec![
+                frame(0, None, "Event SetMap:{}"),
+                frame(
+                    10,
+                    Some("a"),
+                    "Event PlayerState:{\"x\":1,\"damageDealt\":5}",
+                ),
+                frame(15, Some("b"), "Event PlayerState:{\"x\":1}"),
+                frame(20, Some("b"), "Event PlayerState:{\"x\":2}"),
+                frame(
+                    25,
+                    Some("a"),
+                    "Event PlayerState:{\"x\":2,\"damageDealt\":2.5}",
+                ),
+                frame(30, Some("a"), "Event PlayerState:{\"x\":3}"),
+                frame(40, None, "Event StartGame:{}"),
+                frame(
+                    50,
+                    Some("a"),
+                    "Event PlayerState:{\"x\":4,\"damageDealt\":1}",
+                ),
+            ],
+        };
+        assert_eq!(replay.position(30), 5);
+        assert_eq!(replay.position(31), 6);
+        let position = replay.position(45);
+        let caught_up = replay.catch_up(position);
+        let frames: Vec<u64> = caught_up.iter().map(|frame| frame.t).collect();
+        assert_eq!(frames, vec![0, 10, 20, 25, 30, 40]);
+
+        let damage: f64 = caught_up.iter().map(|frame| frame.damage()).sum();
+        let played: f64 = replay.frames[..position]
+            .iter()
+            .map(|frame| frame.damage())
+            .sum();
+        assert_eq!(damage, 7.5);
+        assert_eq!(damage, played);
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::replay::{Direction, Frame, Replay};
+use crate::server::{GameMap, GameMode, MatchOutcome, MatchPhase, Team};
+
+/// Ships are capped at 10 px per physics step and the client steps 60 times a second
+const MAX_SPEED: f64 = 10. * 60. / 1000.;
+/// Clients send their state every 100 ms, frames which arrive closer together were
+/// sent that far apart and held up on the way
+const STATE_INTERVAL: u64 = 100;
+/// Slack for collisions pushing ships around and for timer jitter
+const TOLERANCE: f64 = 1.5;
+const LASER_DAMAGE: f64 = GameMap::LASER_DAMAGE;
+const MAX_DAMAGE_PER_MS: f64 = LASER_DAMAGE / GameMap::LASER_HIT_INTERVAL.as_millis() as f64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DivergenceKind {
+    /// The map does not match the one generated from its seed
+    MAP,
+    /// Damage which could not have been dealt, or totals which do not add up
+    DAMAGE,
+    /// Deaths which do not add up to the dead flags the player sent
+    DEATHS,
+    /// A ship moved faster than it can fly or left the map
+    MOVEMENT,
+    /// The match ended differently than the damage dealt says it should have
+    OUTCOME,
+    /// A frame the server could not have sent in this form
+    MALFORMED,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Milliseconds into the replay
+    pub t: u64,
+    pub kind: DivergenceKind,
+    pub player_id: Option<String>,
+    pub detail: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>8} ms  {:?}", self.t, self.kind)?;
+        if let Some(player_id) = &self.player_id {
+            write!(f, "  {}", player_id)?;
+        }
+        write!(f, "  {}", self.detail)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerJoined {
+    player_id: String,
+    team: Team,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerLeft {
+    player_id: String,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+struct Position {
+    x: f64,
+    y: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerState {
+    position: Option<Position>,
+    #[serde(default)]
+    dead: bool,
+    damage_dealt: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PhaseChanged {
+    phase: MatchPhase,
+    outcome: Option<MatchOutcome>,
+    winner: Option<Team>,
+    results: Option<Vec<PlayerResult>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerResult {
+    player_id: String,
+    damage_dealt: f64,
+    /// Missing from replays recorded before deaths were part of the results
+    deaths: Option<u32>,
+}
+
+struct Ship {
+    team: Team,
+    damage_dealt: f64,
+    deaths: u32,
+    /// Time, position and `dead` flag of the last state the player sent
+    last_state: Option<(u64, Option<Position>, bool)>,
+}
+
+/// Runs the bookkeeping of the server over the frames of a replay
+struct Simulation {
+    mode: GameMode,
+    size: Option<Position>,
+    phase: MatchPhase,
+    ships: HashMap<String, Ship>,
+    /// Outcome and winner once a team destroyed its enemy planet
+    ended: Option<(MatchOutcome, Option<Team>)>,
+    divergences: Vec<Divergence>,
+}
+
+impl Simulation {
+    fn diverge(&mut self, t: u64, kind: DivergenceKind, player_id: Option<&str>, detail: String) {
+        self.divergences.push(Divergence {
+            t,
+            kind,
+            player_id: player_id.map(str::to_string),
+            detail,
+        });
+    }
+
+    fn frame(&mut self, frame: &Frame) {
+        let payload = match frame.m.split_once(':') {
+            Some((_, payload)) => payload,
+            None => return,
+        };
+        let parsed = match (frame.d, frame.event()) {
+            (Direction::OUT, "Event SetMap") => {
+                serde_json::from_str(payload).map(|map| self.set_map(frame.t, map))
+            }
+            (Direction::OUT, "Event PlayerJoinedGame") => {
+                serde_json::from_str(payload).map(|joined: PlayerJoined| {
+                    self.ships.insert(
+                        joined.player_id,
+                        Ship {
+                            team: joined.team,
+                            damage_dealt: 0.,
+                            deaths: 0,
+                            last_state: None,
+                        },
+                    );
+                })
+            }
+            (Direction::OUT, "Event PlayerLeftGame") => {
+                serde_json::from_str(payload).map(|left: PlayerLeft| {
+                    self.ships.remove(&left.player_id);
+                })
+            }
+            (Direction::OUT, "Event MatchPhase") => {
+                serde_json::from_str(payload).map(|phase| self.phase_changed(frame.t, phase))
+            }
+            (Direction::IN, "Event PlayerState") => match &frame.p {
+                Some(player_id) => serde_json::from_str(payload)
+                    .map(|state| self.player_state(frame.t, player_id, state)),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        };
+        if let Err(error) = parsed {
+            let detail = format!("malformed {}: {}", frame.event(), error);
+            self.diverge(
+                frame.t,
+                DivergenceKind::MALFORMED,
+                frame.p.as_deref(),
+                detail,
+            );
+        }
+    }
+
+    fn set_map(&mut self, t: u64, map: Value) {
+        // only race maps have a second enemy planet
+        self.mode = match map.get("rivalEnemyPlanet") {
+            Some(_) => GameMode::RACE,
+            None => GameMode::COOP,
+        };
+        self.size = map
+            .get("size")
+            .and_then(|size| serde_json::from_value(size.clone()).ok());
+        let seed = match map.get("seed").and_then(|seed| seed.as_u64()) {
+            Some(seed) => seed as u32,
+            None => {
+                return self.diverge(t, DivergenceKind::MAP, None, "map has no seed".to_string())
+            }
+        };
+        let generated = serde_json::to_value(GameMap::create_from_seed(self.mode, seed));
+        if generated.ok().as_ref() != Some(&map) {
+            let detail = format!(
+                "map does not match the {:?} map of seed {}",
+                self.mode, seed
+            );
+            self.diverge(t, DivergenceKind::MAP, None, detail);
+        }
+    }
+
+    fn player_state(&mut self, t: u64, player_id: &str, state: PlayerState) {
+        let (team, last_state) = match self.ships.get(player_id) {
+            Some(ship) => (ship.team, ship.last_state),
+            None => {
+                let detail = "state from a player who is not in the room".to_string();
+                return self.diverge(t, DivergenceKind::MALFORMED, Some(player_id), detail);
+            }
+        };
+        let interval = match last_state {
+            Some((last_t, _, _)) => t.saturating_sub(last_t).max(STATE_INTERVAL),
+            None => STATE_INTERVAL,
+        };
+
+        if let (Some((_, Some(from), was_dead)), Some(to)) = (last_state, state.position) {
+            let distance = ((to.x - from.x).powi(2) + (to.y - from.y).powi(2)).sqrt();
+            // dead ships are reset to their spawn, which is a jump
+            if !was_dead && !state.dead && distance > MAX_SPEED * interval as f64 * TOLERANCE {
+                let detail = format!("moved {:.0} px in {} ms", distance, interval);
+                self.diverge(t, DivergenceKind::MOVEMENT, Some(player_id), detail);
+            }
+        }
+        if let (Some(size), Some(position)) = (self.size, state.position) {
+            if position.x < 0. || position.y < 0. || position.x > size.x || position.y > size.y {
+                let detail = format!("left the map at ({:.0}, {:.0})", position.x, position.y);
+                self.diverge(t, DivergenceKind::MOVEMENT, Some(player_id), detail);
+            }
+        }
+
+        let damage = state.damage_dealt.unwrap_or(0.);
+        if damage > 0. {
+            if (damage / LASER_DAMAGE).fract() != 0. {
+                let detail = format!("{} damage is not a number of laser hits", damage);
+                self.diverge(t, DivergenceKind::DAMAGE, Some(player_id), detail);
+            }
+            if damage > MAX_DAMAGE_PER_MS * interval as f64 * TOLERANCE {
+                let detail = format!("dealt {} damage in {} ms", damage, interval);
+                self.diverge(t, DivergenceKind::DAMAGE, Some(player_id), detail);
+            }
+        }
+
+        // the server only counts damage while the match is running
+        let running = self.phase == MatchPhase::RUNNING && self.ended.is_none();
+        if let Some(ship) = self.ships.get_mut(player_id) {
+            ship.last_state = Some((t, state.position, state.dead));
+            if damage > 0. && running {
+                ship.damage_dealt += damage;
+            }
+        }
+        let team_damage: f64 = self
+            .ships
+            .values()
+            .filter(|ship| ship.team == team)
+            .map(|ship| ship.damage_dealt)
+            .sum();
+        if self.ended.is_none() && team_damage >= GameMap::ENEMY_PLANET_HEALTH {
+            let winner = match self.mode {
+                GameMode::RACE => Some(team),
+                GameMode::COOP => None,
+            };
+            self.ended = Some((MatchOutcome::WON, winner));
+        }
+
+        // a death is counted once the state that killed the ship was handled, like the
+        // server does, so a death in the frame that won the match is not counted
+        let was_dead = matches!(last_state, Some((_, _, true)));
+        if state.dead && !was_dead && running && self.ended.is_none() {
+            if let Some(ship) = self.ships.get_mut(player_id) {
+                ship.deaths += 1;
+            }
+        }
+    }
+
+    fn phase_changed(&mut self, t: u64, event: PhaseChanged) {
+        self.phase = event.phase;
+        if event.phase != MatchPhase::ENDED {
+            return;
+        }
+        let (outcome, winner) = self.ended.unwrap_or((MatchOutcome::LOST, None));
+        if event.outcome != Some(outcome) || event.winner != winner {
+            let detail = format!(
+                "server ended the match as {:?} won by {:?}, the damage dealt says {:?} won by {:?}",
+                event.outcome, event.winner, outcome, winner
+            );
+            self.diverge(t, DivergenceKind::OUTCOME, None, detail);
+        }
+        for result in event.results.unwrap_or_default() {
+            let (simulated, deaths) = self
+                .ships
+                .get(&result.player_id)
+                .map(|ship| (ship.damage_dealt, ship.deaths))
+                .unwrap_or((0., 0));
+            if (simulated - result.damage_dealt).abs() > 1e-6 {
+                let detail = format!(
+                    "server counted {} damage, the states add up to {}",
+                    result.damage_dealt, simulated
+                );
+                self.diverge(t, DivergenceKind::DAMAGE, Some(&result.player_id), detail);
+            }
+            match result.deaths {
+                Some(counted) if counted != deaths => {
+                    let detail = format!(
+                        "server counted {} deaths, the states add up to {}",
+                        counted, deaths
+                    );
+                    self.diverge(t, DivergenceKind::DEATHS, Some(&result.player_id), detail);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Re-simulates the match of a replay and returns everywhere it diverges from what the
+/// server recorded or from what a client could have done
+pub fn verify(replay: &Replay) -> Vec<Divergence> {
+    let mut simulation = Simulation {
+        mode: GameMode::default(),
+        size: None,
+        phase: MatchPhase::default(),
+        ships: HashMap::new(),
+        ended: None,
+        divergences: vec![],
+    };
+    for frame in &replay.frames {
+        simulation.frame(frame);
+    }
+    simulation.divergences
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::{ReplayHeader, FORMAT_VERSION};
+    use serde_json::json;
+
+    fn replay(frames: Vec<(u64, Option<&str>, String)>) -> Replay {
+        let map = serde_json::to_string(&GameMap::create_from_seed(GameMode::COOP, 7)).unwrap();
+        let mut all = vec![
+            (0, None, format!("Event SetMap:{}", map)),
+            (0, None, player_joined("a", "ALPHA")),
+            (0, None, player_joined("b", "ALPHA")),
+            (0, None, phase("RUNNING", json!(null), vec![])),
+        ];
+        all.extend(frames);
+        Replay {
+            header: ReplayHeader {
+                version: FORMAT_VERSION,
+                code: "ACDEF".to_string(),
+                started_at: 0,
+            },
+            frames: all
+                .into_iter()
+                .map(|(t, p, m)| Frame {
+                    t,
+                    d: match p {
+                        Some(_) => Direction::IN,
+                        None => Direction::OUT,
+                    },
+                    p: p.map(str::to_string),
+                    m,
+                })
+                .collect(),
+        }
+    }
+
+    fn player_joined(player_id: &str, team: &str) -> String {
+        format!(
+            "Event PlayerJoinedGame:{}",
+            json!({ "playerId": player_id, "team": team })
+        )
+    }
+
+    fn state(x: f64, damage: f64) -> String {
+        format!(
+            "Event PlayerState:{}",
+            json!({ "position": { "x": x, "y": 10000. }, "damageDealt": damage })
+        )
+    }
+
+    fn dead_state(dead: bool) -> String {
+        format!(
+            "Event PlayerState:{}",
+            json!({ "position": { "x": 10000., "y": 10000. }, "dead": dead })
+        )
+    }
+
+    fn phase(phase: &str, outcome: Value, results: Vec<(&str, f64)>) -> String {
+        let results: Vec<Value> = results
+            .into_iter()
+            .map(|(player_id, damage)| json!({ "playerId": player_id, "damageDealt": damage }))
+            .collect();
+        format!(
+            "Event MatchPhase:{}",
+            json!({ "phase": phase, "outcome": outcome, "winner": null, "results": results })
+        )
+    }
+
+    #[test]
+    fn accepts_a_plausible_match() {
+        let mut frames = vec![];
+        for step in 0..10 {
+            let t = 100 + step * 100;
+            frames.push((t, Some("a"), state(10000. + step as f64 * 50., 5.)));
+            frames.push((t, Some("b"), state(10000., 5.)));
+        }
+        frames.push((
+            1000,
+            None,
+            phase("ENDED", json!("WON"), vec![("a", 50.), ("b", 50.)]),
+        ));
+        assert_eq!(verify(&replay(frames)), vec![]);
+    }
+
+    #[test]
+    fn reports_divergences() {
+        let frames = vec![
+            (100, Some("a"), state(10000., 5.)),
+            (200, Some("a"), state(12000., 3.)),
+            (300, Some("b"), state(10000., 90.)),
+            (
+                400,
+                None,
+                phase("ENDED", json!("WON"), vec![("a", 8.), ("b", 80.)]),
+            ),
+        ];
+        let kinds: Vec<(DivergenceKind, Option<String>)> = verify(&replay(frames))
+            .into_iter()
+            .map(|divergence| (divergence.kind, divergence.player_id))
+            .collect();
+        let player = |player_id: &str| Some(player_id.to_string());
+        assert_eq!(
+            kinds,
+            vec![
+                (DivergenceKind::MOVEMENT, player("a")),
+                (DivergenceKind::DAMAGE, player("a")),
+                (DivergenceKind::DAMAGE, player("b")),
+                (DivergenceKind::OUTCOME, None),
+                (DivergenceKind::DAMAGE, player("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_deaths_from_the_dead_flags() {
+        let results = json!([
+            { "playerId": "a", "damageDealt": 0., "deaths": 2 },
+            { "playerId": "b", "damageDealt": 0., "deaths": 0 },
+        ]);
+        let frames = vec![
+            (100, Some("a"), dead_state(true)),
+            (200, Some("a"), dead_state(true)),
+            (300, Some("a"), dead_state(false)),
+            (400, Some("a"), dead_state(true)),
+            (400, Some("b"), dead_state(true)),
+            (
+                500,
+                None,
+                format!(
+                    "Event MatchPhase:{}",
+                    json!({ "phase": "ENDED", "outcome": "LOST", "winner": null, "results": results })
+                ),
+            ),
+        ];
+        let kinds: Vec<(DivergenceKind, Option<String>)> = verify(&replay(frames))
+            .into_iter()
+            .map(|divergence| (divergence.kind, divergence.player_id))
+            .collect();
+        assert_eq!(kinds, vec![(DivergenceKind::DEATHS, Some("b".to_string()))]);
+    }
+}
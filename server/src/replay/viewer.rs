@@ -0,0 +1,216 @@
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_broker::BrokerSubscribe;
+use actix_web_actors::ws;
+use log::{debug, info};
+use serde_json::{json, Value};
+
+use crate::connection_limit::ConnectionLimiter;
+use crate::message::{CloseConnection, CloseSessions};
+use crate::replay::Replay;
+
+const SPEEDS: &[u64] = &[1, 2, 4];
+const TICK: Duration = Duration::from_millis(50);
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Control events are tiny, anything bigger is not worth parsing
+const MAX_FRAME_SIZE: usize = 1024;
+
+/// Streams a recorded match to a websocket client, which sends the same frames the
+/// players of the room received. The client controls playback with `Event ReplayPlay`,
+/// `Event ReplayPause`, `Event ReplaySpeed:{"speed":2}` and `Event ReplaySeek:{"time":ms}`
+/// and is told about every change with `Event ReplayStatus`.
+pub struct ReplayViewer {
+    ip: String,
+    replay: Replay,
+    /// Index of the next frame to send
+    position: usize,
+    /// Milliseconds into the replay
+    time: u64,
+    speed: u64,
+    playing: bool,
+    last_tick: Instant,
+    last_seen: Instant,
+}
+
+impl ReplayViewer {
+    pub fn new(ip: String, replay: Replay) -> Self {
+        ReplayViewer {
+            ip,
+            replay,
+            position: 0,
+            time: 0,
+            speed: 1,
+            playing: true,
+            last_tick: Instant::now(),
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn send_status(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(format!(
+            "Event ReplayStatus:{}",
+            json!({
+                "code": self.replay.header.code,
+                "startedAt": self.replay.header.started_at,
+                "duration": self.replay.duration(),
+                "time": self.time,
+                "speed": self.speed,
+                "playing": self.playing,
+            })
+        ));
+    }
+
+    fn tick(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_tick).as_millis() as u64;
+        self.last_tick = now;
+        if !self.playing {
+            return;
+        }
+        self.time += elapsed * self.speed;
+        while let Some(frame) = self.replay.frames.get(self.position) {
+            if frame.t > self.time {
+                break;
+            }
+            ctx.text(frame.m.clone());
+            self.position += 1;
+        }
+        if self.position >= self.replay.frames.len() {
+            self.playing = false;
+            self.time = self.replay.duration();
+            ctx.text("Event ReplayEnded:{}");
+            self.send_status(ctx);
+        }
+    }
+
+    /// Jumps to `time` and sends what the client needs to show the match at that
+    /// point, after a status event which tells it to reset
+    fn seek(&mut self, time: u64, ctx: &mut ws::WebsocketContext<Self>) {
+        self.time = time.min(self.replay.duration());
+        self.position = self.replay.position(self.time);
+        self.send_status(ctx);
+        for frame in self.replay.catch_up(self.position) {
+            ctx.text(frame.m.clone());
+        }
+    }
+
+    fn control(
+        &mut self,
+        event: &str,
+        payload: Option<Value>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match event {
+            "Event ReplayPlay" => {
+                self.playing = true;
+                if self.position >= self.replay.frames.len() {
+                    return self.seek(0, ctx);
+                }
+            }
+            "Event ReplayPause" => self.playing = false,
+            "Event ReplaySpeed" => {
+                match payload
+                    .and_then(|json| json.get("speed").and_then(|speed| speed.as_u64()))
+                    .filter(|speed| SPEEDS.contains(speed))
+                {
+                    Some(speed) => self.speed = speed,
+                    None => {
+                        return ctx.text(format!(
+                            "Event Warning:{}",
+                            json!({ "reason": format!("speed must be one of {:?}", SPEEDS) })
+                        ))
+                    }
+                }
+            }
+            "Event ReplaySeek" => {
+                if let Some(time) =
+                    payload.and_then(|json| json.get("time").and_then(|time| time.as_u64()))
+                {
+                    return self.seek(time, ctx);
+                }
+            }
+            _ => return ctx.text(format!("!!! unknown event: {:?}", event)),
+        }
+        self.send_status(ctx);
+    }
+}
+
+impl Actor for ReplayViewer {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(
+            "Streaming replay of {} started at {} to {}",
+            self.replay.header.code, self.replay.header.started_at, self.ip
+        );
+        self.subscribe_system_async::<CloseSessions>(ctx);
+        self.send_status(ctx);
+        self.last_tick = Instant::now();
+        ctx.run_interval(TICK, |act, ctx| act.tick(ctx));
+        ctx.run_interval(PING_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.last_seen) > CLIENT_TIMEOUT {
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        ConnectionLimiter::from_registry().do_send(CloseConnection {
+            ip: self.ip.clone(),
+        });
+    }
+}
+
+impl Handler<CloseSessions> for ReplayViewer {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseSessions, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("server is shutting down".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ReplayViewer {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let msg = match msg {
+            Err(_) => {
+                ctx.stop();
+                return;
+            }
+            Ok(msg) => msg,
+        };
+        debug!("REPLAY WEBSOCKET MESSAGE: {:?}", msg);
+        self.last_seen = Instant::now();
+
+        match msg {
+            ws::Message::Text(text) => {
+                if text.len() > MAX_FRAME_SIZE {
+                    return;
+                }
+                let msg = text.trim();
+                let mut command = msg.splitn(2, ':');
+                let event = command.next().unwrap_or_default();
+                if event == "Event Ping" {
+                    return ctx.text(msg);
+                }
+                let payload = command
+                    .next()
+                    .and_then(|payload| serde_json::from_str(payload).ok());
+                self.control(event, payload, ctx);
+            }
+            ws::Message::Ping(bytes) => ctx.pong(&bytes),
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use rand::Rng;
+use serde::Serialize;
+
+/// Letters which cannot be mistaken for each other or for digits when read aloud or
+/// typed from a screenshot, so no I/L/1, O/0, S/5, Z/2, B/8 or U/V
+pub const DEFAULT_ALPHABET: &str = "ACDEFGHJKMNPQRTWXY";
+pub const DEFAULT_LENGTH: usize = 5;
+/// Only words spelled with `DEFAULT_ALPHABET`, anything else can never come up
+const DEFAULT_BLOCKLIST: &[&str] = &[
+    "CRAP", "DAMN", "DYKE", "FAG", "FCK", "JAP", "KKK", "NGR", "RAPE", "TWAT", "WANK", "WTF",
+];
+const MAX_LENGTH: usize = 12;
+/// Gives up instead of spinning forever when the blocklist and the rooms leave no free code
+const MAX_ATTEMPTS: usize = 1000;
+
+pub fn default_blocklist() -> Vec<String> {
+    DEFAULT_BLOCKLIST
+        .iter()
+        .map(|word| word.to_string())
+        .collect()
+}
+
+/// Counts how often generating a free room code needed another attempt
+#[derive(Default, Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomCodeMetrics {
+    pub generated: u64,
+    pub collisions: u64,
+    pub blocked: u64,
+}
+
+/// Generates and validates room codes
+#[derive(Debug, Clone)]
+pub struct RoomCodes {
+    length: usize,
+    alphabet: Vec<char>,
+    blocklist: Vec<String>,
+    metrics: RoomCodeMetrics,
+}
+
+impl Default for RoomCodes {
+    fn default() -> Self {
+        RoomCodes::new(DEFAULT_LENGTH, DEFAULT_ALPHABET, default_blocklist())
+            .expect("default room code configuration is invalid")
+    }
+}
+
+impl RoomCodes {
+    pub fn new(length: usize, alphabet: &str, blocklist: Vec<String>) -> Result<Self, String> {
+        let mut chars: Vec<char> = alphabet.to_uppercase().chars().collect();
+        chars.sort();
+        chars.dedup();
+        if length == 0 || length > MAX_LENGTH {
+            return Err(format!("room codes must be 1 to {} long", MAX_LENGTH));
+        }
+        if chars.len() < 2 || chars.iter().any(|c| !c.is_ascii_alphanumeric()) {
+            return Err(
+                "room code alphabet needs at least two ASCII letters or digits".to_string(),
+            );
+        }
+        Ok(RoomCodes {
+            length,
+            alphabet: chars,
+            blocklist: blocklist
+                .into_iter()
+                .map(|word| word.trim().to_uppercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            metrics: RoomCodeMetrics::default(),
+        })
+    }
+
+    /// Generates a code which is not blocked and for which `is_taken` returns false
+    pub fn generate<F>(&mut self, is_taken: F) -> Result<String, String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut rng = rand::thread_rng();
+        for _ in 0..MAX_ATTEMPTS {
+            let code: String = (0..self.length)
+                .map(|_| self.alphabet[rng.gen_range(0, self.alphabet.len())])
+                .collect();
+            if self.is_blocked(&code) {
+                self.metrics.blocked += 1;
+            } else if is_taken(&code) {
+                self.metrics.collisions += 1;
+            } else {
+                self.metrics.generated += 1;
+                return Ok(code);
+            }
+        }
+        Err("no free room code".to_string())
+    }
+
+    /// Returns the canonical form of a code entered by a player or `None` if the
+    /// code could never have been generated
+    pub fn normalize(&self, code: &str) -> Option<String> {
+        let code = code.trim().to_uppercase();
+        if code.chars().count() != self.length || code.chars().any(|c| !self.alphabet.contains(&c))
+        {
+            return None;
+        }
+        Some(code)
+    }
+
+    pub fn metrics(&self) -> &RoomCodeMetrics {
+        &self.metrics
+    }
+
+    fn is_blocked(&self, code: &str) -> bool {
+        self.blocklist
+            .iter()
+            .any(|word| code.contains(word.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generates_valid_codes() {
+        let mut codes = RoomCodes::default();
+        for _ in 0..100 {
+            let code = codes.generate(|_| false).unwrap();
+            assert_eq!(codes.normalize(&code), Some(code.clone()));
+            assert!(!codes.is_blocked(&code));
+        }
+    }
+
+    #[test]
+    fn normalizes_case_and_rejects_foreign_characters() {
+        let codes = RoomCodes::default();
+        assert_eq!(codes.normalize(" acdef "), Some("ACDEF".to_string()));
+        assert_eq!(codes.normalize("ACDE0"), None);
+        assert_eq!(codes.normalize("ACDEFG"), None);
+    }
+
+    #[test]
+    fn skips_blocked_and_taken_codes() {
+        let mut codes = RoomCodes::new(1, "AB", vec!["a".to_string()]).unwrap();
+        assert_eq!(codes.generate(|_| false), Ok("B".to_string()));
+
+        let mut codes = RoomCodes::new(1, "AB", vec![]).unwrap();
+        let first = codes.generate(|_| false).unwrap();
+        let second = codes.generate(|code| code == first).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(codes.metrics().generated, 2);
+    }
+
+    #[test]
+    fn gives_up_without_free_codes() {
+        let mut codes = RoomCodes::new(1, "AB", vec!["A".to_string()]).unwrap();
+        assert!(codes.generate(|code| code == "B").is_err());
+    }
+
+    #[test]
+    fn default_blocklist_fits_the_default_alphabet() {
+        for word in DEFAULT_BLOCKLIST {
+            assert!(
+                word.chars().all(|c| DEFAULT_ALPHABET.contains(c)),
+                "{} can never be generated",
+                word
+            );
+        }
+    }
+}
@@ -1,170 +1,346 @@
-use rand::distributions::Alphanumeric;
-use rand::{random, thread_rng, Rng};
-
-use log::info;
+use log::{error, info};
 
 use actix::prelude::*;
-use actix_broker::BrokerSubscribe;
+use actix_broker::BrokerIssue;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use futures::channel::oneshot;
+use futures::future::{self, FutureExt};
 
+mod chat;
 mod events;
 mod game_objects;
 mod map;
+mod names;
 mod planet;
+mod room;
+mod snapshot;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::achievements::Achievement;
+use crate::directory::{Node, RoomDirectory, HEARTBEAT_INTERVAL};
 use crate::message::{
-    CreateGame, GameMessage, GameState, JoinGame, LeaveGame, ListGames, Message, StartGame,
+    CloseRoom, CloseSessions, CreateGame, GetAchievements, GetLeaderboard, JoinGame, Joined,
+    ListGames, Message, RecentMatches, ResumeGame, RoomClosed, RoomCodeStats, RoomPhase,
+    ServerShutdown, Shutdown,
 };
-use crate::server::events::{JoinedGame, PlayerType, SetMapGameEvent};
-use crate::server::game_objects::{Coordinates, GameMap};
-use events::{
-    GameStateEvent, MultiplayerEvent, PlayerJoinedGameEvent, PlayerLeftGameEvent, RoomLeaderEvent,
+use crate::replay::Replays;
+use crate::room_code::RoomCodes;
+use crate::server::chat::ChatFilter;
+pub use crate::server::chat::WordListFilter;
+pub use crate::server::events::PlayerType;
+use crate::server::events::{MultiplayerEvent, RedirectEvent};
+pub use crate::server::game_objects::{
+    Coordinates, Emote, GameMode, MarkerKind, MatchOutcome, Team,
 };
-use serde::export::Option::Some;
+pub use crate::server::game_objects::{GameMap, MatchPhase};
+use crate::server::room::Game;
+pub use crate::server::room::GameRoom;
+pub use crate::server::snapshot::{RoomSnapshot, Snapshot};
+use crate::storage::{LeaderboardEntry, MatchRecord, MatchStore};
 
 type Client = Recipient<Message>;
 
-#[derive(Default, Debug)]
-pub struct Game {
-    players: HashMap<String, Player>,
-    leader: Option<String>,
-    secret: Option<String>,
-    map: GameMap,
-    started: bool,
+/// What every room needs from the server, shared by all of them
+#[derive(Clone, Default)]
+struct Services {
+    chat_filter: Option<Arc<dyn ChatFilter>>,
+    store: Option<Arc<Mutex<Box<dyn MatchStore>>>>,
+    replays: Option<Replays>,
 }
 
-#[derive(Debug)]
-pub struct Player {
-    client: Client,
-    player_type: PlayerType,
-    spawn: Coordinates,
+impl Services {
+    /// Runs `query` on the thread pool, SQLite blocks the thread it runs on
+    fn use_store<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut dyn MatchStore) -> Result<T, String> + Send + 'static,
+    ) -> Option<impl Future<Output = Result<T, String>>> {
+        let store = self.store.clone()?;
+        let result = web::block(move || match store.lock() {
+            Ok(mut store) => query(store.as_mut()),
+            Err(_) => Err("match store is poisoned".to_string()),
+        });
+        Some(result.map(|result| {
+            result.map_err(|error| match error {
+                BlockingError::Error(error) => error,
+                BlockingError::Canceled => "match store query was canceled".to_string(),
+            })
+        }))
+    }
 }
 
+struct RoomEntry {
+    addr: Addr<GameRoom>,
+    phase: MatchPhase,
+}
+
+/// Keeps track of the rooms of this node. Each room runs as its own actor, the
+/// server only hands out codes and sends players to the right room.
 #[derive(Default)]
 pub struct WsGameServer {
-    games: HashMap<String, Game>,
+    rooms: HashMap<String, RoomEntry>,
+    /// Rooms taken over from a snapshot, started along with the server
+    restored: HashMap<String, Game>,
+    services: Services,
+    room_codes: RoomCodes,
+    /// Threads the rooms are spread over, rooms run on the thread of the server if empty
+    arbiters: Vec<Arbiter>,
+    next_arbiter: usize,
+    /// Where the rooms are saved to on shutdown
+    snapshot_file: Option<PathBuf>,
+    /// This node and the directory it shares its rooms through with other nodes
+    directory: Option<(Node, Box<dyn RoomDirectory>)>,
+    /// Server time in ms at which a shutdown stops waiting for running matches
+    shutdown: Option<u64>,
+    drained: Option<oneshot::Sender<()>>,
 }
 
 impl WsGameServer {
-    const CODE_CHARS: &'static [u8] = b"ABCDEFGHKLMNOPRSTUVWXYZ";
-
-    fn add_player_to_game(
-        &mut self,
-        game_name: &str,
-        client: Client,
-    ) -> (String, PlayerType, Coordinates) {
-        let mut id = rand::random::<usize>().to_string();
-        let mut player_type: PlayerType = random();
-
-        let game = self
-            .games
-            .entry(game_name.to_owned())
-            .or_insert_with(Game::default);
-        loop {
-            if game.players.contains_key(&id) {
-                id = rand::random::<usize>().to_string();
-            } else {
-                break;
-            }
+    const SHUTDOWN_CHECK: Duration = Duration::from_secs(1);
+    const SHUTDOWN_ANNOUNCE: Duration = Duration::from_secs(10);
+    /// How long restored players have to reconnect before their place is given up
+    const RESUME_WINDOW: Duration = Duration::from_secs(2 * 60);
+    /// Codes taken by other nodes are not known to the generator, so it gets a few tries
+    const CLAIM_ATTEMPTS: usize = 10;
+
+    pub fn with_chat_filter(mut self, chat_filter: Box<dyn ChatFilter>) -> Self {
+        self.services.chat_filter = Some(Arc::from(chat_filter));
+        self
+    }
+
+    pub fn with_store(mut self, store: Box<dyn MatchStore>) -> Self {
+        self.services.store = Some(Arc::new(Mutex::new(store)));
+        self
+    }
+
+    pub fn with_room_codes(mut self, room_codes: RoomCodes) -> Self {
+        self.room_codes = room_codes;
+        self
+    }
+
+    pub fn with_replays(mut self, replays: Replays) -> Self {
+        self.services.replays = Some(replays);
+        self
+    }
+
+    pub fn with_snapshot_file(mut self, path: PathBuf) -> Self {
+        self.snapshot_file = Some(path);
+        self
+    }
+
+    /// Runs the rooms on their own threads instead of the thread of the server
+    pub fn with_room_threads(mut self, threads: usize) -> Self {
+        self.arbiters = (0..threads).map(|_| Arbiter::new()).collect();
+        self
+    }
+
+    /// Shares room codes with other nodes. Rooms restored before are claimed right
+    /// away and dropped if another node took over their code in the meantime.
+    pub fn with_directory(mut self, node: Node, directory: Box<dyn RoomDirectory>) -> Self {
+        if let Err(error) = directory.heartbeat(&node) {
+            error!("Failed to announce node {}: {}", node.id, error);
         }
-        loop {
-            if game
-                .players
-                .iter()
-                .any(|(_, p)| p.player_type == player_type)
-            {
-                player_type = random();
-            } else {
-                break;
-            }
+        if let Err(error) = directory.release_node(&node) {
+            error!("Failed to release the rooms of node {}: {}", node.id, error);
         }
-        game.players.iter().for_each(|(player_id, player)| {
-            client
-                .do_send(Message(
-                    PlayerJoinedGameEvent {
-                        player_id: player_id.clone(),
-                        player_type: player.player_type.clone(),
-                        spawn: player.spawn.clone(),
-                    }
-                    .to_message(),
-                ))
-                .ok();
-        });
-        let spawn = game.map.get_spawn_for_player(game.players.len());
-        let player = Player {
-            client,
-            player_type: player_type.clone(),
-            spawn: spawn.clone(),
+        self.restored
+            .retain(|code, _| match directory.claim(code, &node) {
+                Ok(true) => true,
+                Ok(false) => {
+                    info!("Dropping restored game {}, another node owns it", code);
+                    false
+                }
+                Err(error) => {
+                    error!("Failed to claim restored game {}: {}", code, error);
+                    false
+                }
+            });
+        self.directory = Some((node, directory));
+        self
+    }
+
+    /// Takes over the rooms of a previous server, unless they are too old to be resumed
+    pub fn with_snapshot(mut self, snapshot: Snapshot) -> Self {
+        let age = server_time().saturating_sub(snapshot.saved_at);
+        if age > Self::RESUME_WINDOW.as_millis() as u64 {
+            info!("Ignoring snapshot from {}s ago", age / 1000);
+            return self;
+        }
+        info!("Restoring {} rooms", snapshot.rooms.len());
+        for room in snapshot.rooms {
+            self.restored.insert(room.code.clone(), Game::restore(room));
+        }
+        self
+    }
+
+    /// Starts the actor of a room, taking turns between the room threads
+    fn spawn_room(&mut self, code: String, game: Game, ctx: &mut Context<Self>) -> Addr<GameRoom> {
+        let phase = game.phase();
+        let registry = ctx.address();
+        let services = self.services.clone();
+        let room_code = code.clone();
+        let create =
+            move |_: &mut Context<GameRoom>| GameRoom::new(room_code, game, registry, services);
+        let addr = if self.arbiters.is_empty() {
+            GameRoom::create(create)
+        } else {
+            let arbiter = &self.arbiters[self.next_arbiter % self.arbiters.len()];
+            self.next_arbiter = self.next_arbiter.wrapping_add(1);
+            GameRoom::start_in_arbiter(arbiter, create)
         };
-        game.players.insert(id.clone(), player);
-        (id, player_type, spawn)
+        self.rooms.insert(
+            code,
+            RoomEntry {
+                addr: addr.clone(),
+                phase,
+            },
+        );
+        addr
     }
 
-    fn send_message_to_game(&mut self, game_name: &str, msg: &str, src: &String) -> Option<()> {
-        let mut game = self.games.remove(game_name)?;
-        let players = game
-            .players
-            .drain()
-            .filter(|(player_id, player)| {
-                if player_id == src {
-                    return true;
-                }
-                player.client.do_send(Message(msg.to_owned())).is_ok()
-            })
-            .collect();
-        game.players = players;
-        self.games.insert(game_name.to_owned(), game);
-
-        Some(())
-    }
-
-    fn send_message_to_player(&self, recipient: &String, msg: &str) -> Option<()> {
-        for (_game_name, game) in self.games.iter() {
-            if game.players.contains_key(recipient) {
-                let player = game
-                    .players
-                    .get(recipient)
-                    .expect("failed to find expected player in game");
-                player
-                    .client
-                    .do_send(Message(msg.to_owned()))
-                    .expect("Failed to send message to player");
+    /// Picks a code which no room on any node uses
+    fn new_room_code(&mut self) -> Result<String, String> {
+        for _ in 0..Self::CLAIM_ATTEMPTS {
+            let rooms = &self.rooms;
+            let code = self.room_codes.generate(|code| rooms.contains_key(code))?;
+            let claimed = match &self.directory {
+                Some((node, directory)) => directory.claim(&code, node)?,
+                None => true,
+            };
+            if claimed {
+                return Ok(code);
+            }
+        }
+        Err("no free room code".to_string())
+    }
+
+    /// Keeps the codes of this node from being taken over by other nodes
+    fn heartbeat(&self) {
+        if let Some((node, directory)) = &self.directory {
+            if let Err(error) = directory.heartbeat(node) {
+                error!("Failed to announce node {}: {}", node.id, error);
             }
         }
+    }
 
-        Some(())
+    fn release_room(&self, code: &str) {
+        if let Some((node, directory)) = &self.directory {
+            if let Err(error) = directory.release(code, node) {
+                error!("Failed to release game {}: {}", code, error);
+            }
+        }
     }
 
-    fn make_player_leader(&mut self, player_id: &String, game_name: String) -> Option<()> {
-        let secret = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(10)
-            .collect::<String>();
-        self.send_message_to_player(
-            &player_id,
-            &RoomLeaderEvent {
-                secret: secret.clone(),
+    /// Sends the client to the node which owns the room, if that is not this one
+    fn redirect(&self, code: &str, client: &Client) -> Result<(), String> {
+        if self.rooms.contains_key(code) {
+            return Ok(());
+        }
+        let (node, directory) = match &self.directory {
+            Some(directory) => directory,
+            None => return Ok(()),
+        };
+        match directory.owner(code)? {
+            Some(owner) if owner.id != node.id => {
+                client
+                    .do_send(Message(
+                        RedirectEvent {
+                            code: code.to_owned(),
+                            url: owner.url,
+                        }
+                        .to_message(),
+                    ))
+                    .ok();
+                Err("game is hosted on another node".to_string())
             }
-            .to_message(),
-        )
-        .expect("failed to send initialisation message for game room");
-        let mut game = self.games.remove(&game_name)?;
-        game.leader = Some(player_id.clone());
-        game.secret = Some(secret);
-        self.games.insert(game_name, game);
+            _ => Ok(()),
+        }
+    }
 
-        Some(())
+    /// Looks up the room a player wants to get into
+    fn find_room(&self, game_name: &str, client: &Client) -> Result<Addr<GameRoom>, String> {
+        if self.shutdown.is_some() {
+            return Err("server is shutting down".to_string());
+        }
+        let code = self
+            .room_codes
+            .normalize(game_name)
+            .ok_or_else(|| "code invalid".to_string())?;
+        self.redirect(&code, client)?;
+        match self.rooms.get(&code) {
+            Some(room) => Ok(room.addr.clone()),
+            None => Err("code invalid".to_string()),
+        }
     }
 
-    fn create_code() -> String {
-        let mut rng = thread_rng();
-        (0..5)
-            .map(|_| {
-                let idx = rng.gen_range(0, Self::CODE_CHARS.len());
-                Self::CODE_CHARS[idx] as char
+    /// Finishes a shutdown once no match is in progress anymore or the deadline passed
+    fn drain(&mut self, ctx: &mut Context<Self>) {
+        let deadline = match self.shutdown {
+            Some(deadline) if self.drained.is_some() => deadline,
+            _ => return,
+        };
+        let running = self
+            .rooms
+            .values()
+            .filter(|room| room.phase == MatchPhase::COUNTDOWN || room.phase == MatchPhase::RUNNING)
+            .count();
+        if running > 0 && server_time() < deadline {
+            return;
+        }
+        if running > 0 {
+            info!("Shutting down with {} matches still running", running);
+        }
+        let drained = self.drained.take();
+        let codes: Vec<String> = self.rooms.keys().cloned().collect();
+        // unfinished matches are not recorded, but what was played is kept as a replay
+        let closing = self
+            .rooms
+            .drain()
+            .map(|(_, room)| room.addr.send(CloseRoom));
+        future::join_all(closing)
+            .into_actor(self)
+            .map(move |closed, act, _ctx| {
+                let rooms: Vec<RoomSnapshot> = closed
+                    .into_iter()
+                    .filter_map(|room| room.ok().flatten())
+                    .collect();
+                act.save_snapshot(rooms);
+                for code in codes {
+                    act.release_room(&code);
+                }
+                act.issue_system_async(CloseSessions);
+                if let Some(drained) = drained {
+                    let _ = drained.send(());
+                }
             })
-            .collect()
+            .spawn(ctx);
+    }
+
+    /// Reads from the match store, which rooms write to from their own threads
+    fn query_store<T: Default + Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut dyn MatchStore) -> Result<T, String> + Send + 'static,
+    ) -> ResponseFuture<Result<T, String>> {
+        match self.services.use_store(query) {
+            Some(result) => Box::pin(result),
+            None => Box::pin(future::ready(Ok(T::default()))),
+        }
+    }
+
+    fn save_snapshot(&self, rooms: Vec<RoomSnapshot>) {
+        let path = match &self.snapshot_file {
+            Some(path) => path,
+            None => return,
+        };
+        let count = rooms.len();
+        match Snapshot::new(server_time(), rooms).save(path) {
+            Ok(()) => info!("Saved {} rooms to {}", count, path.display()),
+            Err(error) => error!("Failed to save rooms to {}: {}", path.display(), error),
+        }
     }
 }
 
@@ -172,188 +348,311 @@ impl Actor for WsGameServer {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.subscribe_system_async::<LeaveGame>(ctx);
-        self.subscribe_system_async::<GameMessage>(ctx);
-        self.subscribe_system_async::<GameState>(ctx);
-        self.subscribe_system_async::<StartGame>(ctx);
+        let restored: Vec<(String, Game)> = self.restored.drain().collect();
+        for (code, game) in restored {
+            self.spawn_room(code, game, ctx);
+        }
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, _ctx| act.heartbeat());
     }
 }
 
+/// Forwards a join to its room, a room which closed in the meantime is gone
+fn forward<M>(
+    room: Result<Addr<GameRoom>, String>,
+    msg: M,
+) -> ResponseFuture<Result<Joined, String>>
+where
+    M: actix::Message<Result = Result<Joined, String>> + Send + 'static,
+    GameRoom: Handler<M>,
+{
+    let room = match room {
+        Ok(room) => room,
+        Err(error) => return Box::pin(future::ready(Err(error))),
+    };
+    Box::pin(room.send(msg).map(|joined| match joined {
+        Ok(joined) => joined,
+        Err(_) => Err("code invalid".to_string()),
+    }))
+}
+
 impl Handler<JoinGame> for WsGameServer {
-    type Result = Result<String, String>;
+    type Result = ResponseFuture<Result<Joined, String>>;
 
     fn handle(&mut self, msg: JoinGame, _ctx: &mut Self::Context) -> Self::Result {
-        let JoinGame { game_name, player } = msg;
+        let room = self.find_room(&msg.game_name, &msg.player);
+        forward(room, msg)
+    }
+}
 
-        if let Some(game) = self.games.get(&game_name) {
-            if game.started {
-                return Err("game is running".to_string());
-            }
-            if game.map.player_cap == game.players.len() {
-                return Err("game is full".to_string());
-            }
-            let (id, player_type, spawn) = self.add_player_to_game(&game_name, player);
-            let game = self.games.get(&game_name).expect("Failed to get room");
-
-            self.send_message_to_player(
-                &id,
-                &JoinedGame {
-                    ok: true,
-                    reason: None,
-                    code: Some(game_name.clone()),
-                    player_type: Some(player_type.clone()),
-                    spawn: Some(spawn.clone()),
-                }
-                .to_message(),
-            );
-            self.send_message_to_player(&id, &SetMapGameEvent { map: &game.map }.to_message());
-            if game.leader.is_none() {
-                info!("Making {} leader of game {}", id, &game_name);
-                self.make_player_leader(&id, String::from(&game_name));
-            }
-            self.send_message_to_game(
-                &game_name,
-                &PlayerJoinedGameEvent {
-                    player_id: id.clone(),
-                    player_type,
-                    spawn,
-                }
-                .to_message(),
-                &id,
-            );
-            return Ok(id);
-        }
-        return Err("code invalid".to_string());
+impl Handler<ResumeGame> for WsGameServer {
+    type Result = ResponseFuture<Result<Joined, String>>;
+
+    fn handle(&mut self, msg: ResumeGame, _ctx: &mut Self::Context) -> Self::Result {
+        let room = self.find_room(&msg.game_name, &msg.player);
+        forward(room, msg)
     }
 }
 
 impl Handler<CreateGame> for WsGameServer {
-    type Result = Result<(String, String), String>;
+    type Result = ResponseFuture<Result<Joined, String>>;
 
     fn handle(&mut self, msg: CreateGame, ctx: &mut Self::Context) -> Self::Result {
-        let CreateGame { player } = msg;
-
-        let mut code = Self::create_code();
-        while self.games.get(&code).is_some() {
-            code = Self::create_code();
+        let CreateGame {
+            player,
+            disconnect,
+            name,
+            account_name,
+            player_type,
+            account_id,
+            mode,
+            duration,
+        } = msg;
+        if self.shutdown.is_some() {
+            return Box::pin(future::ready(Err("server is shutting down".to_string())));
         }
-
-        self.games.insert(code.clone(), Game::default());
-
-        let join = self.handle(
+        let code = match self.new_room_code() {
+            Ok(code) => code,
+            Err(error) => return Box::pin(future::ready(Err(error))),
+        };
+        let room = self.spawn_room(code.clone(), Game::new(mode, duration), ctx);
+        forward(
+            Ok(room),
             JoinGame {
                 player,
-                game_name: code.clone(),
+                disconnect,
+                game_name: code,
+                spectator: false,
+                name,
+                account_name,
+                player_type,
+                account_id,
             },
-            ctx,
-        );
-
-        match join {
-            Ok(id) => Ok((id, code)),
-            Err(reason) => Err(reason),
-        }
+        )
     }
 }
 
-impl Handler<LeaveGame> for WsGameServer {
+impl Handler<RoomPhase> for WsGameServer {
     type Result = ();
 
-    fn handle(&mut self, msg: LeaveGame, _ctx: &mut Self::Context) {
-        let mut removed_player: Option<Client> = None;
-        let mut new_lead: Option<String> = None;
-        if let Some(room) = self.games.get_mut(&msg.game_name) {
-            removed_player = room
-                .players
-                .remove(&msg.player_id)
-                .map(|player| -> Client { player.client });
-            if room.leader == Some(msg.player_id.clone()) {
-                if room.players.len() < 1 {
-                    self.games.remove(&msg.game_name);
-                    return;
-                }
-
-                if let Some((player_id, _client)) = room.players.iter().next() {
-                    new_lead = Some(player_id.clone());
-                }
-            }
-        }
-        if removed_player.is_some() {
-            info!("Removing {} from game {:?}", msg.player_id, msg.game_name);
-            self.send_message_to_game(
-                &msg.game_name,
-                &PlayerLeftGameEvent {
-                    player_id: msg.player_id.clone(),
-                }
-                .to_message(),
-                &msg.player_id,
-            );
-        }
-        if let Some(player_id) = new_lead {
-            self.make_player_leader(&player_id, msg.game_name.clone());
+    fn handle(&mut self, msg: RoomPhase, _ctx: &mut Self::Context) {
+        if let Some(room) = self.rooms.get_mut(&msg.code) {
+            room.phase = msg.phase;
         }
     }
 }
 
-impl Handler<GameState> for WsGameServer {
+impl Handler<RoomClosed> for WsGameServer {
     type Result = ();
 
-    fn handle(&mut self, msg: GameState, _ctx: &mut Self::Context) {
-        let GameState {
-            game_name,
-            sender_id,
-            secret,
-            payload,
-        } = msg;
-        if let Some(room) = self.games.get(&game_name) {
-            if room.leader == Some(sender_id.clone()) && room.secret == Some(secret) {
-                self.send_message_to_game(
-                    &game_name,
-                    &GameStateEvent { payload }.to_message(),
-                    &sender_id.clone(),
-                );
-            }
+    fn handle(&mut self, msg: RoomClosed, _ctx: &mut Self::Context) {
+        if self.rooms.remove(&msg.code).is_some() {
+            info!("Game {} closed", msg.code);
+            self.release_room(&msg.code);
         }
     }
 }
 
-impl Handler<StartGame> for WsGameServer {
-    type Result = ();
+impl Handler<ListGames> for WsGameServer {
+    type Result = MessageResult<ListGames>;
 
-    fn handle(&mut self, msg: StartGame, _ctx: &mut Self::Context) {
-        let StartGame {
-            secret,
-            sender_id,
-            game_name,
-        } = msg;
-        if let Some(room) = self.games.get_mut(&game_name) {
-            if room.leader == Some(sender_id.clone()) && room.secret == Some(secret) {
-                room.started = true;
-                self.send_message_to_game(&game_name, "Event StartGame:{}", &sender_id.clone());
-            }
-        }
+    fn handle(&mut self, _: ListGames, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.rooms.keys().cloned().collect())
     }
 }
 
-impl Handler<ListGames> for WsGameServer {
-    type Result = MessageResult<ListGames>;
+impl Handler<RecentMatches> for WsGameServer {
+    type Result = ResponseFuture<Result<Vec<MatchRecord>, String>>;
 
-    fn handle(&mut self, _: ListGames, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(self.games.keys().cloned().collect())
+    fn handle(&mut self, msg: RecentMatches, _ctx: &mut Self::Context) -> Self::Result {
+        self.query_store(move |store| store.recent_matches(msg.limit))
+    }
+}
+
+impl Handler<GetLeaderboard> for WsGameServer {
+    type Result = ResponseFuture<Result<Vec<LeaderboardEntry>, String>>;
+
+    fn handle(&mut self, msg: GetLeaderboard, _ctx: &mut Self::Context) -> Self::Result {
+        self.query_store(move |store| store.leaderboard(&msg.query))
+    }
+}
+
+impl Handler<GetAchievements> for WsGameServer {
+    type Result = ResponseFuture<Result<Vec<Achievement>, String>>;
+
+    fn handle(&mut self, msg: GetAchievements, _ctx: &mut Self::Context) -> Self::Result {
+        self.query_store(move |store| store.achievements(&msg.account_id))
+    }
+}
+
+impl Handler<RoomCodeStats> for WsGameServer {
+    type Result = MessageResult<RoomCodeStats>;
+
+    fn handle(&mut self, _: RoomCodeStats, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.room_codes.metrics().clone())
     }
 }
 
-impl Handler<GameMessage> for WsGameServer {
+pub(crate) fn server_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl Handler<Shutdown> for WsGameServer {
     type Result = ();
 
-    fn handle(&mut self, msg: GameMessage, _ctx: &mut Self::Context) {
-        let GameMessage {
-            game_name,
-            message,
-            sender_id,
-        } = msg;
-        self.send_message_to_game(&game_name, &message, &sender_id);
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Self::Context) {
+        let deadline = server_time() + msg.grace.as_millis() as u64;
+        info!(
+            "Shutting down, waiting up to {:?} for {} games",
+            msg.grace,
+            self.rooms.len()
+        );
+        self.shutdown = Some(deadline);
+        self.drained = Some(msg.drained);
+        self.issue_system_async(ServerShutdown { deadline });
+        ctx.run_interval(Self::SHUTDOWN_ANNOUNCE, move |act, _ctx| {
+            if act.drained.is_some() {
+                act.issue_system_async(ServerShutdown { deadline });
+            }
+        });
+        ctx.run_interval(Self::SHUTDOWN_CHECK, |act, ctx| act.drain(ctx));
+        self.drain(ctx);
     }
 }
 
 impl SystemService for WsGameServer {}
 impl Supervised for WsGameServer {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::Disconnect;
+
+    /// Stands in for a player session
+    pub(super) struct Sink;
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Sink {
+        type Result = ();
+
+        fn handle(&mut self, _msg: Message, _ctx: &mut Self::Context) {}
+    }
+
+    impl Handler<Disconnect> for Sink {
+        type Result = ();
+
+        fn handle(&mut self, _msg: Disconnect, _ctx: &mut Self::Context) {}
+    }
+
+    fn join_game(sink: &Addr<Sink>, code: &str) -> JoinGame {
+        JoinGame {
+            game_name: code.to_string(),
+            player: sink.clone().recipient(),
+            disconnect: sink.clone().recipient(),
+            spectator: false,
+            name: None,
+            account_name: None,
+            player_type: None,
+            account_id: None,
+        }
+    }
+
+    fn create_game(sink: &Addr<Sink>) -> CreateGame {
+        CreateGame {
+            player: sink.clone().recipient(),
+            disconnect: sink.clone().recipient(),
+            name: None,
+            account_name: None,
+            player_type: None,
+            account_id: None,
+            mode: GameMode::COOP,
+            duration: None,
+        }
+    }
+
+    #[test]
+    fn hands_players_to_rooms_on_other_threads() {
+        System::new("test").block_on(async {
+            let server = WsGameServer::default().with_room_threads(2).start();
+            let sink = Sink.start();
+            let created = server.send(create_game(&sink)).await.unwrap().unwrap();
+            let code = created.game_name.clone();
+            assert_eq!(server.send(ListGames).await.unwrap(), vec![code.clone()]);
+
+            let joined = server.send(join_game(&sink, &code)).await.unwrap().unwrap();
+            assert!(joined.room == created.room);
+            assert_ne!(joined.player_id, created.player_id);
+            assert!(server
+                .send(join_game(&sink, "nope"))
+                .await
+                .unwrap()
+                .is_err());
+
+            assert!(created.room.send(CloseRoom).await.unwrap().is_some());
+            // the room reports that it stopped from its own thread
+            for _ in 0..100 {
+                if server.send(ListGames).await.unwrap().is_empty() {
+                    break;
+                }
+                actix_rt::time::delay_for(Duration::from_millis(10)).await;
+            }
+            assert!(server.send(ListGames).await.unwrap().is_empty());
+            assert!(server.send(join_game(&sink, &code)).await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn drains_once_no_match_is_running() {
+        let path = std::env::temp_dir().join(format!("rooms-{}.json", crate::token::player_id()));
+        let snapshot_file = path.clone();
+        let code = System::new("test").block_on(async move {
+            let server = WsGameServer::default()
+                .with_snapshot_file(snapshot_file)
+                .start();
+            let sink = Sink.start();
+            let code = server
+                .send(create_game(&sink))
+                .await
+                .unwrap()
+                .unwrap()
+                .game_name;
+            server
+                .send(RoomPhase {
+                    code: code.clone(),
+                    phase: MatchPhase::RUNNING,
+                })
+                .await
+                .unwrap();
+
+            let (drained, mut on_drained) = oneshot::channel();
+            server
+                .send(Shutdown {
+                    grace: Duration::from_secs(60),
+                    drained,
+                })
+                .await
+                .unwrap();
+            assert!(server.send(create_game(&sink)).await.unwrap().is_err());
+            assert_eq!(on_drained.try_recv(), Ok(None));
+
+            server
+                .send(RoomPhase {
+                    code: code.clone(),
+                    phase: MatchPhase::ENDED,
+                })
+                .await
+                .unwrap();
+            on_drained.await.unwrap();
+            assert!(server.send(ListGames).await.unwrap().is_empty());
+            code
+        });
+        let snapshot = Snapshot::take(&path).unwrap().unwrap();
+        assert_eq!(snapshot.rooms.len(), 1);
+        assert_eq!(snapshot.rooms[0].code, code);
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const MAX_MESSAGE_LENGTH: usize = 200;
+pub const HISTORY_LENGTH: usize = 20;
+
+/// Hook to moderate chat messages before they are sent to the room
+pub trait ChatFilter: Send + Sync {
+    /// Returns the message that should be sent instead or the reason for blocking it
+    fn filter(&self, message: &str) -> Result<String, String>;
+}
+
+/// Masks all words from a block list
+pub struct WordListFilter {
+    words: HashSet<String>,
+}
+
+impl WordListFilter {
+    pub fn new<I: IntoIterator<Item = String>>(words: I) -> Self {
+        WordListFilter {
+            words: words
+                .into_iter()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Reads one blocked word per line
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(Self::new(content.lines().map(String::from)))
+    }
+}
+
+impl ChatFilter for WordListFilter {
+    fn filter(&self, message: &str) -> Result<String, String> {
+        Ok(message
+            .split(' ')
+            .map(|word| {
+                let bare = word.trim_matches(|c: char| !c.is_alphanumeric());
+                if self.words.contains(&bare.to_lowercase()) {
+                    "*".repeat(word.chars().count())
+                } else {
+                    word.to_string()
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" "))
+    }
+}
+
+/// Trims the message and checks that it is neither empty nor too long
+pub fn validate_message(message: &str) -> Result<String, String> {
+    let message = message.trim();
+    if message.is_empty() {
+        return Err("message is empty".to_string());
+    }
+    if message.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(format!(
+            "message is longer than {} characters",
+            MAX_MESSAGE_LENGTH
+        ));
+    }
+    Ok(message.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn word_list_filter_masks_blocked_words() {
+        let filter = WordListFilter::new(vec!["darn".to_string()]);
+        assert_eq!(
+            filter.filter("Darn, that was close").unwrap(),
+            "***** that was close"
+        );
+        assert_eq!(filter.filter("darning socks").unwrap(), "darning socks");
+    }
+
+    #[test]
+    fn rejects_empty_and_long_messages() {
+        assert!(validate_message("   ").is_err());
+        assert!(validate_message(&"a".repeat(MAX_MESSAGE_LENGTH + 1)).is_err());
+        assert_eq!(validate_message(" hi ").unwrap(), "hi");
+    }
+}
@@ -1,8 +1,9 @@
-use crate::server::game_objects::{Coordinates, GameMap};
-use rand::distributions::{Distribution, Standard};
-use rand::Rng;
+use crate::achievements::{Achievement, Skin};
+use crate::server::game_objects::{
+    Coordinates, Emote, GameMap, MarkerKind, MatchOutcome, MatchPhase, Team,
+};
 use serde;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Default, Debug, Serialize)]
 pub struct RoomLeaderEvent {
@@ -13,8 +14,12 @@ pub struct RoomLeaderEvent {
 #[serde(rename_all = "camelCase")]
 pub struct PlayerJoinedGameEvent {
     pub player_id: String,
+    pub name: String,
     pub player_type: PlayerType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skin: Option<Skin>,
     pub spawn: Coordinates,
+    pub team: Team,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -23,6 +28,158 @@ pub struct PlayerLeftGameEvent {
     pub player_id: String,
 }
 
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamChangedEvent {
+    pub player_id: String,
+    pub team: Team,
+    pub spawn: Coordinates,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyPlayer {
+    pub player_id: String,
+    pub name: String,
+    pub player_type: PlayerType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skin: Option<Skin>,
+    pub team: Team,
+    pub ready: bool,
+    pub leader: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyStateEvent {
+    pub players: Vec<LobbyPlayer>,
+    pub spectators: usize,
+    pub locked: bool,
+    pub max_players: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColorChangedEvent {
+    pub player_id: String,
+    pub player_type: PlayerType,
+}
+
+/// `skin` is null when the player went back to their plain colour
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkinChangedEvent {
+    pub player_id: String,
+    pub skin: Option<Skin>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AchievementUnlockedEvent {
+    pub achievement: Achievement,
+    pub skin: Skin,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderChangedEvent {
+    pub player_id: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatEvent {
+    pub player_id: String,
+    pub name: String,
+    pub message: String,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkerEvent {
+    pub player_id: String,
+    pub kind: MarkerKind,
+    pub position: Coordinates,
+    /// Milliseconds until clients should remove the marker
+    pub ttl: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmoteEvent {
+    pub player_id: String,
+    pub emote: Emote,
+    /// Milliseconds until clients should remove the emote
+    pub ttl: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChatRejectedEvent {
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KickedEvent {
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UnauthorizedEvent {
+    pub action: String,
+    pub reason: String,
+}
+
+/// Sent instead of joining when another node owns the room
+#[derive(Clone, Debug, Serialize)]
+pub struct RedirectEvent {
+    pub code: String,
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StartGameRefusedEvent {
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchPhaseEvent {
+    pub phase: MatchPhase,
+    /// Server time of the transition in milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// Server time at which the phase times out, if it does
+    pub ends_at: Option<u64>,
+    pub outcome: Option<MatchOutcome>,
+    pub winner: Option<Team>,
+    /// Only set once the match ended
+    pub results: Option<Vec<PlayerResult>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerResult {
+    pub player_id: String,
+    pub name: String,
+    pub player_type: PlayerType,
+    pub team: Team,
+    pub damage_dealt: f64,
+    pub deaths: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerSpawn {
+    pub player_id: String,
+    pub team: Team,
+    pub spawn: Coordinates,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SpawnsEvent {
+    pub spawns: Vec<PlayerSpawn>,
+}
+
 #[derive(Debug)]
 pub struct SetMapGameEvent<'a> {
     pub map: &'a GameMap,
@@ -39,11 +196,17 @@ pub struct JoinedGame {
     pub ok: bool,
     pub reason: Option<String>,
     pub code: Option<String>,
+    pub name: Option<String>,
     pub player_type: Option<PlayerType>,
     pub spawn: Option<Coordinates>,
+    pub team: Option<Team>,
+    pub spectator: bool,
+    /// Lets the player take its place again after a server restart
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
 }
 
-#[derive(Debug, Serialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum PlayerType {
     BLUE,
     RED,
@@ -57,21 +220,20 @@ pub enum PlayerType {
     TURQUOISE,
 }
 
-impl Distribution<PlayerType> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PlayerType {
-        match rng.gen_range(0, 10) {
-            0 => PlayerType::BLUE,
-            1 => PlayerType::RED,
-            2 => PlayerType::YELLOW,
-            3 => PlayerType::GREEN,
-            4 => PlayerType::GRAY,
-            5 => PlayerType::LIGHTBLUE,
-            6 => PlayerType::ORANGE,
-            7 => PlayerType::PINK,
-            8 => PlayerType::PURPLE,
-            _ => PlayerType::TURQUOISE,
-        }
-    }
+impl PlayerType {
+    /// The colour pool in the order free colours are handed out
+    pub const ALL: [PlayerType; 10] = [
+        PlayerType::BLUE,
+        PlayerType::RED,
+        PlayerType::YELLOW,
+        PlayerType::GREEN,
+        PlayerType::GRAY,
+        PlayerType::LIGHTBLUE,
+        PlayerType::ORANGE,
+        PlayerType::PINK,
+        PlayerType::PURPLE,
+        PlayerType::TURQUOISE,
+    ];
 }
 
 pub trait MultiplayerEvent {
@@ -108,6 +270,120 @@ impl MultiplayerEvent for PlayerLeftGameEvent {
     }
 }
 
+impl MultiplayerEvent for TeamChangedEvent {
+    fn to_message(&self) -> String {
+        format!("Event TeamChanged:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for LobbyStateEvent {
+    fn to_message(&self) -> String {
+        format!("Event LobbyState:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for ColorChangedEvent {
+    fn to_message(&self) -> String {
+        format!(
+            "Event ColorChanged:{}",
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+impl MultiplayerEvent for SkinChangedEvent {
+    fn to_message(&self) -> String {
+        format!("Event SkinChanged:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for AchievementUnlockedEvent {
+    fn to_message(&self) -> String {
+        format!(
+            "Event AchievementUnlocked:{}",
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+impl MultiplayerEvent for LeaderChangedEvent {
+    fn to_message(&self) -> String {
+        format!(
+            "Event LeaderChanged:{}",
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+impl MultiplayerEvent for ChatEvent {
+    fn to_message(&self) -> String {
+        format!("Event Chat:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for MarkerEvent {
+    fn to_message(&self) -> String {
+        format!("Event Marker:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for EmoteEvent {
+    fn to_message(&self) -> String {
+        format!("Event Emote:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for ChatRejectedEvent {
+    fn to_message(&self) -> String {
+        format!(
+            "Event ChatRejected:{}",
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+impl MultiplayerEvent for KickedEvent {
+    fn to_message(&self) -> String {
+        format!("Event Kicked:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for UnauthorizedEvent {
+    fn to_message(&self) -> String {
+        format!(
+            "Event Unauthorized:{}",
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+impl MultiplayerEvent for RedirectEvent {
+    fn to_message(&self) -> String {
+        format!("Event Redirect:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for StartGameRefusedEvent {
+    fn to_message(&self) -> String {
+        format!(
+            "Event StartGameRefused:{}",
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+impl MultiplayerEvent for MatchPhaseEvent {
+    fn to_message(&self) -> String {
+        format!("Event MatchPhase:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+impl MultiplayerEvent for SpawnsEvent {
+    fn to_message(&self) -> String {
+        format!("Event Spawns:{}", serde_json::to_string(self).unwrap())
+    }
+}
+
 impl MultiplayerEvent for SetMapGameEvent<'_> {
     fn to_message(&self) -> String {
         format!("Event SetMap:{}", serde_json::to_string(self.map).unwrap())
@@ -1,7 +1,7 @@
 use rand::distributions::Standard;
 use rand::prelude::Distribution;
 use rand::Rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -11,6 +11,11 @@ pub struct GameMap {
     pub player_cap: usize,
     pub spawns: Vec<Coordinates>,
     pub enemy_planet: Planet,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rival_spawns: Option<Vec<Coordinates>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rival_enemy_planet: Option<Planet>,
+    pub seed: u32,
 }
 
 #[derive(Debug, Serialize)]
@@ -21,7 +26,7 @@ pub struct Planet {
     pub planet_type: PlanetType,
 }
 
-#[derive(Default, Debug, Serialize, Clone)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Coordinates {
     pub x: usize,
     pub y: usize,
@@ -46,3 +51,46 @@ impl Distribution<PlanetType> for Standard {
         }
     }
 }
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum GameMode {
+    #[default]
+    COOP,
+    RACE,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Team {
+    ALPHA,
+    BETA,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MarkerKind {
+    ATTACK,
+    DANGER,
+    HELP,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Emote {
+    WAVE,
+    THANKS,
+    SORRY,
+    GG,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MatchPhase {
+    #[default]
+    LOBBY,
+    COUNTDOWN,
+    RUNNING,
+    ENDED,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MatchOutcome {
+    WON,
+    LOST,
+}
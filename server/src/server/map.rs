@@ -1,7 +1,9 @@
 use log::info;
+use std::time::Duration;
 
-use crate::server::game_objects::{Coordinates, GameMap, Planet, PlanetType};
-use rand::{random, thread_rng, Rng};
+use crate::server::game_objects::{Coordinates, GameMap, GameMode, Planet, PlanetType, Team};
+use rand::rngs::StdRng;
+use rand::{random, Rng, SeedableRng};
 
 impl GameMap {
     const PLANET_RADIUS: usize = 125;
@@ -12,8 +14,18 @@ impl GameMap {
     const OUTER_BOUNDS: usize = 10 * Self::MAP_TILE_SIZE;
     const INNER_AREA: (usize, usize) = (35 * Self::MAP_TILE_SIZE, 65 * Self::MAP_TILE_SIZE);
     const MAP_SIZE: usize = Self::MAP_TILE_SIZE * Self::MAP_NUMBER_OF_TILES;
-    pub fn create_random() -> Self {
-        let mut rng = thread_rng();
+    pub const ENEMY_PLANET_HEALTH: f64 = 100.0;
+    /// Damage of one laser hit on an enemy planet in a multiplayer match
+    pub const LASER_DAMAGE: f64 = 5.;
+    /// Both lasers fire at most every 80 ms, so a ship lands at most one hit per interval
+    pub const LASER_HIT_INTERVAL: Duration = Duration::from_millis(40);
+
+    pub fn create_random(mode: GameMode) -> Self {
+        Self::create_from_seed(mode, random())
+    }
+
+    pub fn create_from_seed(mode: GameMode, seed: u32) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed as u64);
         let enemy_planet = Planet {
             planet_type: PlanetType::EARTH,
             position: Coordinates {
@@ -22,61 +34,109 @@ impl GameMap {
             },
             radius: Self::PLANET_RADIUS,
         };
+        let rival_enemy_planet = match mode {
+            GameMode::COOP => None,
+            GameMode::RACE => Some(Self::place_rival_enemy_planet(&mut rng, &enemy_planet)),
+        };
+        let spawns = Self::spawn_cluster();
+        let rival_spawns = match mode {
+            GameMode::COOP => None,
+            GameMode::RACE => Some(
+                spawns
+                    .iter()
+                    .map(|spawn| Coordinates {
+                        x: Self::MAP_SIZE - spawn.x,
+                        y: Self::MAP_SIZE - spawn.y,
+                    })
+                    .collect(),
+            ),
+        };
+        let mut enemy_planets = vec![&enemy_planet];
+        if let Some(rival) = &rival_enemy_planet {
+            enemy_planets.push(rival);
+        }
         GameMap {
             size: Coordinates {
                 x: Self::MAP_SIZE,
                 y: Self::MAP_SIZE,
             },
-            planets: Self::place_random_planets(&enemy_planet),
+            planets: Self::place_random_planets(&mut rng, &enemy_planets),
             player_cap: 10,
+            spawns,
+            rival_spawns,
+            rival_enemy_planet,
             enemy_planet,
-            spawns: vec![
-                Coordinates {
-                    x: 7 * Self::MAP_TILE_SIZE,
-                    y: 7 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 8 * Self::MAP_TILE_SIZE,
-                    y: 7 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 7 * Self::MAP_TILE_SIZE,
-                    y: 8 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 8 * Self::MAP_TILE_SIZE,
-                    y: 8 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 9 * Self::MAP_TILE_SIZE,
-                    y: 7 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 9 * Self::MAP_TILE_SIZE,
-                    y: 8 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 7 * Self::MAP_TILE_SIZE,
-                    y: 9 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 8 * Self::MAP_TILE_SIZE,
-                    y: 9 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 9 * Self::MAP_TILE_SIZE,
-                    y: 9 * Self::MAP_TILE_SIZE,
-                },
-                Coordinates {
-                    x: 10 * Self::MAP_TILE_SIZE,
-                    y: 7 * Self::MAP_TILE_SIZE,
-                },
-            ],
+            seed,
+        }
+    }
+
+    fn spawn_cluster() -> Vec<Coordinates> {
+        vec![
+            Coordinates {
+                x: 7 * Self::MAP_TILE_SIZE,
+                y: 7 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 8 * Self::MAP_TILE_SIZE,
+                y: 7 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 7 * Self::MAP_TILE_SIZE,
+                y: 8 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 8 * Self::MAP_TILE_SIZE,
+                y: 8 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 9 * Self::MAP_TILE_SIZE,
+                y: 7 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 9 * Self::MAP_TILE_SIZE,
+                y: 8 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 7 * Self::MAP_TILE_SIZE,
+                y: 9 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 8 * Self::MAP_TILE_SIZE,
+                y: 9 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 9 * Self::MAP_TILE_SIZE,
+                y: 9 * Self::MAP_TILE_SIZE,
+            },
+            Coordinates {
+                x: 10 * Self::MAP_TILE_SIZE,
+                y: 7 * Self::MAP_TILE_SIZE,
+            },
+        ]
+    }
+
+    fn place_rival_enemy_planet(rng: &mut StdRng, enemy_planet: &Planet) -> Planet {
+        let mut position = Coordinates {
+            x: Self::MAP_SIZE - enemy_planet.position.x,
+            y: Self::MAP_SIZE - enemy_planet.position.y,
+        };
+        let mut tries = 0;
+        while !Self::does_fit_with_planets(&[], enemy_planet, position.x, position.y) && tries < 20
+        {
+            position = Coordinates {
+                x: rng.gen_range(Self::INNER_AREA.0, Self::INNER_AREA.1),
+                y: rng.gen_range(Self::INNER_AREA.0, Self::INNER_AREA.1),
+            };
+            tries += 1;
+        }
+        Planet {
+            planet_type: PlanetType::EARTH,
+            position,
+            radius: Self::PLANET_RADIUS,
         }
     }
 
-    fn place_random_planets(enemy_planet: &Planet) -> Vec<Planet> {
-        let mut rng = thread_rng();
+    fn place_random_planets(rng: &mut StdRng, enemy_planets: &[&Planet]) -> Vec<Planet> {
         let mut planets: Vec<Planet> = vec![];
         for _ in 0..Self::NUMBER_OF_PLANETS {
             let mut y: usize =
@@ -84,7 +144,7 @@ impl GameMap {
             let mut x: usize =
                 rng.gen_range(Self::OUTER_BOUNDS, Self::MAP_SIZE - Self::OUTER_BOUNDS);
             let mut tries = 0;
-            while !Self::does_fit_with_planets(&planets, enemy_planet, x, y) {
+            while !Self::does_fit_with_enemy_planets(&planets, enemy_planets, x, y) {
                 if tries > 20 {
                     info!("tried to place a planet more than 20 times");
                     break;
@@ -99,9 +159,9 @@ impl GameMap {
                 );
                 tries += 1;
             }
-            if Self::does_fit_with_planets(&planets, enemy_planet, x, y) {
+            if Self::does_fit_with_enemy_planets(&planets, enemy_planets, x, y) {
                 planets.push(Planet {
-                    planet_type: random(),
+                    planet_type: rng.gen(),
                     position: Coordinates { x, y },
                     radius: Self::PLANET_RADIUS,
                 })
@@ -111,8 +171,19 @@ impl GameMap {
         planets
     }
 
+    fn does_fit_with_enemy_planets(
+        planets: &[Planet],
+        enemy_planets: &[&Planet],
+        x: usize,
+        y: usize,
+    ) -> bool {
+        enemy_planets
+            .iter()
+            .all(|enemy_planet| Self::does_fit_with_planets(planets, enemy_planet, x, y))
+    }
+
     fn does_fit_with_planets(
-        planets: &Vec<Planet>,
+        planets: &[Planet],
         enemy_planet: &Planet,
         x: usize,
         y: usize,
@@ -145,8 +216,18 @@ impl GameMap {
             .is_none()
     }
 
-    pub fn get_spawn_for_player(&self, player_number: usize) -> Coordinates {
-        let spawn = self.spawns.get(player_number);
+    pub fn spawns_for(&self, team: Team) -> &[Coordinates] {
+        match (team, &self.rival_spawns) {
+            (Team::BETA, Some(rival_spawns)) => rival_spawns,
+            _ => &self.spawns,
+        }
+    }
+
+    pub fn get_spawn_for_player(&self, team: Team, taken: &[&Coordinates]) -> Coordinates {
+        let spawn = self
+            .spawns_for(team)
+            .iter()
+            .find(|spawn| !taken.contains(spawn));
         match spawn {
             Some(spawn) => Coordinates {
                 x: spawn.x,
@@ -162,7 +243,7 @@ impl GameMap {
 
 impl Default for GameMap {
     fn default() -> Self {
-        GameMap::create_random()
+        GameMap::create_random(GameMode::default())
     }
 }
 
@@ -282,4 +363,14 @@ mod test {
             "Cannot be too far down"
         );
     }
+
+    #[test]
+    fn same_seed_creates_same_map() {
+        let first = GameMap::create_from_seed(GameMode::RACE, 42);
+        let second = GameMap::create_from_seed(GameMode::RACE, 42);
+        assert_eq!(
+            serde_json::to_string(&first).unwrap(),
+            serde_json::to_string(&second).unwrap()
+        );
+    }
 }
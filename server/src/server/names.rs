@@ -0,0 +1,60 @@
+pub const MIN_NAME_LENGTH: usize = 2;
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// Trims the display name and checks its length and characters
+pub fn validate_display_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    let length = name.chars().count();
+    if !(MIN_NAME_LENGTH..=MAX_NAME_LENGTH).contains(&length) {
+        return Err(format!(
+            "name must be between {} and {} characters",
+            MIN_NAME_LENGTH, MAX_NAME_LENGTH
+        ));
+    }
+    if name
+        .chars()
+        .any(|c| !(c.is_ascii_alphanumeric() || c == ' ' || c == '_' || c == '-'))
+    {
+        return Err("name may only contain letters, digits, spaces, _ and -".to_string());
+    }
+    if name.contains("  ") {
+        return Err("name may not contain consecutive spaces".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// Names are unique per room regardless of case
+pub fn same_name(first: &str, second: &str) -> bool {
+    first.to_lowercase() == second.to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accepts_and_trims_valid_names() {
+        assert_eq!(
+            validate_display_name(" Ace_Pilot-7 ").unwrap(),
+            "Ace_Pilot-7"
+        );
+        assert_eq!(validate_display_name("Star Lord").unwrap(), "Star Lord");
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert!(validate_display_name("a").is_err(), "Too short");
+        assert!(
+            validate_display_name(&"a".repeat(MAX_NAME_LENGTH + 1)).is_err(),
+            "Too long"
+        );
+        assert!(validate_display_name("<script>").is_err(), "Markup");
+        assert!(validate_display_name("a  b").is_err(), "Consecutive spaces");
+    }
+
+    #[test]
+    fn compares_names_case_insensitive() {
+        assert!(same_name("Maverick", "mAVERICK"));
+        assert!(!same_name("Maverick", "Goose"));
+    }
+}
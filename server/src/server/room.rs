@@ -0,0 +1,1957 @@
+use log::{error, info, warn};
+
+use actix::prelude::*;
+use actix_broker::BrokerSubscribe;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::achievements::{self, Skin};
+use crate::message::{
+    ChangeColor, ChangeSkin, Chat, CloseRoom, Disconnect, GameMessage, GameState, JoinGame, Joined,
+    KickPlayer, LeaveGame, LockRoom, Message, MutePlayer, PlaceMarker, PlayerDied, PlayerState,
+    Rematch, ReportLatency, ResumeGame, RoomClosed, RoomPhase, SendEmote, ServerShutdown,
+    SetMaxPlayers, SetReady, SetTeam, StartGame, TransferLeadership,
+};
+use crate::rate_limit::TokenBucket;
+use crate::replay::{self, Direction, Recorder, Replays};
+use crate::server::chat;
+use crate::server::events::{
+    AchievementUnlockedEvent, ChatEvent, ChatRejectedEvent, ColorChangedEvent, EmoteEvent,
+    GameStateEvent, JoinedGame, KickedEvent, LeaderChangedEvent, LobbyPlayer, LobbyStateEvent,
+    MarkerEvent, MatchPhaseEvent, MultiplayerEvent, PlayerJoinedGameEvent, PlayerLeftGameEvent,
+    PlayerResult, PlayerSpawn, PlayerType, RoomLeaderEvent, SetMapGameEvent, SkinChangedEvent,
+    SpawnsEvent, StartGameRefusedEvent, TeamChangedEvent, UnauthorizedEvent,
+};
+use crate::server::game_objects::{Coordinates, GameMap, GameMode, MatchOutcome, MatchPhase, Team};
+use crate::server::names;
+use crate::server::snapshot::{PlayerSnapshot, RoomSnapshot};
+use crate::server::{server_time, Services, WsGameServer};
+use crate::storage::{MatchRecord, PlayerRecord};
+use crate::token;
+
+type Client = Recipient<Message>;
+
+#[derive(Debug)]
+pub struct Game {
+    players: HashMap<String, Player>,
+    spectators: HashMap<String, Client>,
+    leader: Option<String>,
+    secret: Option<String>,
+    /// Counts joins so players can be ordered by the time they joined
+    joins: u64,
+    map: GameMap,
+    mode: GameMode,
+    locked: bool,
+    max_players: usize,
+    phase: MatchPhase,
+    /// Incremented on every phase transition to invalidate pending phase timers
+    phase_epoch: u64,
+    duration: Duration,
+    /// Server time in ms at which the running phase began
+    started_at: Option<u64>,
+    outcome: Option<MatchOutcome>,
+    winner: Option<Team>,
+    chat_history: VecDeque<ChatEvent>,
+    /// Writes the replay of the current match, from its countdown until it ended
+    recorder: Option<Recorder>,
+    /// Players restored from a snapshot who did not resume their place yet
+    absent: HashMap<String, PlayerSnapshot>,
+}
+
+#[derive(Debug)]
+pub struct Player {
+    client: Client,
+    disconnect: Recipient<Disconnect>,
+    account_id: Option<String>,
+    resume_token: String,
+    name: String,
+    player_type: PlayerType,
+    skin: Option<Skin>,
+    spawn: Coordinates,
+    team: Team,
+    damage_dealt: f64,
+    deaths: u32,
+    ready: bool,
+    joined: u64,
+    latency: Option<Duration>,
+    muted: bool,
+    chat_limiter: TokenBucket,
+    /// Counts laser hits, reported damage beyond what the lasers can fire is dropped
+    damage_limiter: TokenBucket,
+    last_quick_ping: Option<Instant>,
+}
+
+impl Player {
+    const QUICK_PING_COOLDOWN: Duration = Duration::from_secs(2);
+    /// Hits one state frame may carry, with the slack replay verification allows
+    const DAMAGE_BURST: u32 = 4;
+
+    /// A player who just joined, the `joined` count orders players by their join
+    fn new(
+        client: Client,
+        disconnect: Recipient<Disconnect>,
+        name: String,
+        player_type: PlayerType,
+        spawn: Coordinates,
+        team: Team,
+        joined: u64,
+    ) -> Self {
+        Player {
+            client,
+            disconnect,
+            account_id: None,
+            resume_token: token::secret(),
+            name,
+            player_type,
+            skin: None,
+            spawn,
+            team,
+            damage_dealt: 0.,
+            deaths: 0,
+            ready: false,
+            joined,
+            latency: None,
+            muted: false,
+            chat_limiter: TokenBucket::new(5, Duration::from_secs(2)),
+            damage_limiter: TokenBucket::new(Self::DAMAGE_BURST, GameMap::LASER_HIT_INTERVAL),
+            last_quick_ping: None,
+        }
+    }
+
+    /// Counts reported damage in whole laser hits, at most as many as the lasers could fire
+    fn count_damage(&mut self, damage: f64) -> f64 {
+        let hits = (damage / GameMap::LASER_DAMAGE) as u32;
+        f64::from(self.damage_limiter.take_up_to(hits)) * GameMap::LASER_DAMAGE
+    }
+
+    fn joined_event(&self, player_id: &str) -> PlayerJoinedGameEvent {
+        PlayerJoinedGameEvent {
+            player_id: player_id.to_owned(),
+            name: self.name.clone(),
+            player_type: self.player_type.clone(),
+            skin: self.skin,
+            spawn: self.spawn.clone(),
+            team: self.team,
+        }
+    }
+
+    fn snapshot(&self, player_id: &str) -> PlayerSnapshot {
+        PlayerSnapshot {
+            player_id: player_id.to_owned(),
+            resume_token: self.resume_token.clone(),
+            account_id: self.account_id.clone(),
+            name: self.name.clone(),
+            player_type: self.player_type.clone(),
+            skin: self.skin,
+            spawn: self.spawn.clone(),
+            team: self.team,
+            damage_dealt: self.damage_dealt,
+            deaths: self.deaths,
+            ready: self.ready,
+            joined: self.joined,
+        }
+    }
+
+    fn restore(
+        snapshot: PlayerSnapshot,
+        client: Client,
+        disconnect: Recipient<Disconnect>,
+    ) -> Self {
+        Player {
+            account_id: snapshot.account_id,
+            resume_token: snapshot.resume_token,
+            skin: snapshot.skin,
+            damage_dealt: snapshot.damage_dealt,
+            deaths: snapshot.deaths,
+            ready: snapshot.ready,
+            ..Player::new(
+                client,
+                disconnect,
+                snapshot.name,
+                snapshot.player_type,
+                snapshot.spawn,
+                snapshot.team,
+                snapshot.joined,
+            )
+        }
+    }
+
+    /// Markers and emotes share one cooldown
+    fn try_quick_ping(&mut self) -> bool {
+        let now = Instant::now();
+        match self.last_quick_ping {
+            Some(last) if now.duration_since(last) < Self::QUICK_PING_COOLDOWN => false,
+            _ => {
+                self.last_quick_ping = Some(now);
+                true
+            }
+        }
+    }
+}
+
+impl Game {
+    const COUNTDOWN: Duration = Duration::from_secs(3);
+    const RESULTS: Duration = Duration::from_secs(10);
+    const DEFAULT_DURATION: Duration = Duration::from_secs(10 * 60);
+    const MIN_DURATION: Duration = Duration::from_secs(60);
+    const MAX_DURATION: Duration = Duration::from_secs(60 * 60);
+
+    pub(super) fn new(mode: GameMode, duration: Option<Duration>) -> Self {
+        let map = GameMap::create_random(mode);
+        Game {
+            max_players: map.player_cap,
+            locked: false,
+            map,
+            mode,
+            phase: MatchPhase::default(),
+            phase_epoch: 0,
+            duration: duration
+                .unwrap_or(Self::DEFAULT_DURATION)
+                .max(Self::MIN_DURATION)
+                .min(Self::MAX_DURATION),
+            started_at: None,
+            outcome: None,
+            winner: None,
+            players: HashMap::new(),
+            spectators: HashMap::new(),
+            leader: None,
+            secret: None,
+            joins: 0,
+            chat_history: VecDeque::with_capacity(chat::HISTORY_LENGTH),
+            recorder: None,
+            absent: HashMap::new(),
+        }
+    }
+
+    fn snapshot(&self, code: &str) -> RoomSnapshot {
+        RoomSnapshot {
+            code: code.to_owned(),
+            mode: self.mode,
+            seed: self.map.seed,
+            duration_ms: self.duration.as_millis() as u64,
+            locked: self.locked,
+            max_players: self.max_players,
+            phase: self.phase,
+            leader: self.leader.clone(),
+            joins: self.joins,
+            outcome: self.outcome,
+            winner: self.winner,
+            players: self
+                .players
+                .iter()
+                .map(|(player_id, player)| player.snapshot(player_id))
+                .chain(self.absent.values().cloned())
+                .collect(),
+        }
+    }
+
+    pub(super) fn phase(&self) -> MatchPhase {
+        self.phase
+    }
+
+    /// Rebuilds a room from a snapshot, all of its players are absent until they resume
+    pub(super) fn restore(room: RoomSnapshot) -> Self {
+        let mut game = Game::new(room.mode, Some(Duration::from_millis(room.duration_ms)));
+        game.map = GameMap::create_from_seed(room.mode, room.seed);
+        game.locked = room.locked;
+        game.max_players = room.max_players;
+        game.leader = room.leader;
+        game.joins = room.joins;
+        // a match which was still going when the server went down cannot be continued
+        let ended = room.phase == MatchPhase::ENDED;
+        if ended {
+            game.phase = MatchPhase::ENDED;
+            game.outcome = room.outcome;
+            game.winner = room.winner;
+        }
+        game.absent = room
+            .players
+            .into_iter()
+            .map(|mut player| {
+                if !ended {
+                    player.damage_dealt = 0.;
+                    player.deaths = 0;
+                    player.ready = false;
+                }
+                (player.player_id.clone(), player)
+            })
+            .collect();
+        game
+    }
+
+    fn phase_timeout(&self) -> Option<Duration> {
+        match self.phase {
+            MatchPhase::LOBBY => None,
+            MatchPhase::COUNTDOWN => Some(Self::COUNTDOWN),
+            MatchPhase::RUNNING => Some(self.duration),
+            MatchPhase::ENDED => Some(Self::RESULTS),
+        }
+    }
+
+    /// Leadership is bound to the session's player id. The secret is an optional
+    /// second factor, but when it is sent it has to match.
+    fn authorize(&self, player_id: &str, secret: Option<&str>) -> Result<(), String> {
+        if self.leader.as_deref() != Some(player_id) {
+            return Err("only the room leader may do this".to_string());
+        }
+        match (secret, &self.secret) {
+            (None, _) => Ok(()),
+            (Some(secret), Some(leader_secret))
+                if token::constant_time_eq(leader_secret, secret) =>
+            {
+                Ok(())
+            }
+            _ => Err("invalid leader secret".to_string()),
+        }
+    }
+
+    /// Picks the player with the best connection as the next leader. Latencies are
+    /// compared in buckets of 50ms, so that similar connections fall back to join order.
+    fn next_leader(&self) -> Option<String> {
+        self.players
+            .iter()
+            .min_by_key(|(_, player)| {
+                let latency = player
+                    .latency
+                    .map(|latency| latency.as_millis() / 50)
+                    .unwrap_or(u128::MAX);
+                (latency, player.joined)
+            })
+            .map(|(player_id, _)| player_id.clone())
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.players.contains_key(id)
+            || self.spectators.contains_key(id)
+            || self.absent.contains_key(id)
+    }
+
+    /// Players that still need to ready up before the leader can start
+    fn not_ready(&self) -> usize {
+        self.players
+            .iter()
+            .filter(|(player_id, player)| !player.ready && self.leader.as_ref() != Some(*player_id))
+            .count()
+    }
+
+    fn lobby_state(&self) -> LobbyStateEvent {
+        LobbyStateEvent {
+            players: self
+                .players
+                .iter()
+                .map(|(player_id, player)| LobbyPlayer {
+                    player_id: player_id.clone(),
+                    name: player.name.clone(),
+                    player_type: player.player_type.clone(),
+                    skin: player.skin,
+                    team: player.team,
+                    ready: player.ready,
+                    leader: self.leader.as_ref() == Some(player_id),
+                })
+                .collect(),
+            spectators: self.spectators.len(),
+            locked: self.locked,
+            max_players: self.max_players,
+        }
+    }
+
+    fn results(&self) -> Vec<PlayerResult> {
+        self.players
+            .iter()
+            .map(|(player_id, player)| PlayerResult {
+                player_id: player_id.clone(),
+                name: player.name.clone(),
+                player_type: player.player_type.clone(),
+                team: player.team,
+                damage_dealt: player.damage_dealt,
+                deaths: player.deaths,
+            })
+            .chain(self.absent.values().map(|player| PlayerResult {
+                player_id: player.player_id.clone(),
+                name: player.name.clone(),
+                player_type: player.player_type.clone(),
+                team: player.team,
+                damage_dealt: player.damage_dealt,
+                deaths: player.deaths,
+            }))
+            .collect()
+    }
+
+    fn phase_event(&self, timestamp: u64, ends_at: Option<u64>) -> MatchPhaseEvent {
+        MatchPhaseEvent {
+            phase: self.phase,
+            timestamp,
+            ends_at,
+            outcome: self.outcome,
+            winner: self.winner,
+            results: match self.phase {
+                MatchPhase::ENDED => Some(self.results()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Hands out the requested colour if it is free and the first free colour otherwise
+    fn free_player_type(&self, requested: Option<PlayerType>) -> Option<PlayerType> {
+        let is_free = |player_type: &PlayerType| {
+            !self
+                .players
+                .values()
+                .any(|player| &player.player_type == player_type)
+        };
+        requested
+            .filter(|player_type| is_free(player_type))
+            .or_else(|| {
+                PlayerType::ALL
+                    .iter()
+                    .find(|player_type| is_free(player_type))
+                    .cloned()
+            })
+    }
+
+    /// Gives an absent player their place back. It is only given up once they are sure
+    /// to get in, as someone who joined in the meantime may have taken the name or colour.
+    fn take_absent(&mut self, resume_token: &str) -> Result<PlayerSnapshot, String> {
+        let absent = self
+            .absent
+            .values()
+            .find(|absent| token::constant_time_eq(&absent.resume_token, resume_token))
+            .ok_or_else(|| "invalid resume token".to_string())?;
+        // like joins, absent players are held back until the match is over
+        if self.phase == MatchPhase::COUNTDOWN || self.phase == MatchPhase::RUNNING {
+            return Err("game is running".to_string());
+        }
+        let player_type = self
+            .free_player_type(Some(absent.player_type.clone()))
+            .ok_or_else(|| "game is full".to_string())?;
+        let id = absent.player_id.clone();
+        let mut snapshot = self
+            .absent
+            .remove(&id)
+            .expect("Failed to get absent player");
+        if self.is_name_taken(&snapshot.name) {
+            snapshot.name = self.pilot_name();
+        }
+        snapshot.player_type = player_type;
+        Ok(snapshot)
+    }
+
+    fn is_name_taken(&self, name: &str) -> bool {
+        self.players
+            .values()
+            .any(|player| names::same_name(&player.name, name))
+    }
+
+    /// Validates the requested name or comes up with a free default name
+    fn display_name(
+        &self,
+        requested: Option<String>,
+        account_name: Option<String>,
+    ) -> Result<String, String> {
+        match requested {
+            Some(name) => {
+                let name = names::validate_display_name(&name)?;
+                if self.is_name_taken(&name) {
+                    return Err("name is already taken".to_string());
+                }
+                Ok(name)
+            }
+            None => Ok(self.default_name(account_name)),
+        }
+    }
+
+    /// Names players after their account, unless someone in the room already took it
+    fn default_name(&self, account_name: Option<String>) -> String {
+        account_name
+            .and_then(|name| names::validate_display_name(&name).ok())
+            .filter(|name| !self.is_name_taken(name))
+            .unwrap_or_else(|| self.pilot_name())
+    }
+
+    fn pilot_name(&self) -> String {
+        (1..)
+            .map(|number| format!("Pilot {}", number))
+            .find(|name| !self.is_name_taken(name))
+            .expect("Failed to find a free name")
+    }
+
+    fn team_size(&self, team: Team) -> usize {
+        self.players
+            .values()
+            .filter(|player| player.team == team)
+            .count()
+    }
+
+    /// Damage taken by the enemy planet `team` attacks, hits on any other planet are
+    /// never counted
+    fn team_damage(&self, team: Team) -> f64 {
+        self.players
+            .values()
+            .filter(|player| player.team == team)
+            .map(|player| player.damage_dealt)
+            .sum()
+    }
+
+    /// Coop games keep everyone on one team, race games fill up the smaller team
+    fn balanced_team(&self) -> Team {
+        match self.mode {
+            GameMode::COOP => Team::ALPHA,
+            GameMode::RACE => {
+                if self.team_size(Team::BETA) < self.team_size(Team::ALPHA) {
+                    Team::BETA
+                } else {
+                    Team::ALPHA
+                }
+            }
+        }
+    }
+
+    fn reassign_spawns(&mut self) {
+        let mut assigned: Vec<(Team, Coordinates)> = vec![];
+        for player in self.players.values_mut() {
+            let taken: Vec<&Coordinates> = assigned
+                .iter()
+                .filter(|(team, _)| *team == player.team)
+                .map(|(_, spawn)| spawn)
+                .collect();
+            player.spawn = self.map.get_spawn_for_player(player.team, &taken);
+            assigned.push((player.team, player.spawn.clone()));
+        }
+    }
+
+    fn free_spawn(&self, team: Team) -> Coordinates {
+        let taken: Vec<&Coordinates> = self
+            .players
+            .values()
+            .filter(|player| player.team == team)
+            .map(|player| &player.spawn)
+            .collect();
+        self.map.get_spawn_for_player(team, &taken)
+    }
+}
+
+/// Runs a single room. Every room is its own actor, so a busy room does not hold up
+/// the others and rooms can be spread over several threads.
+pub struct GameRoom {
+    code: String,
+    game: Game,
+    registry: Addr<WsGameServer>,
+    services: Services,
+    /// Set once the server shuts down, no match may start after that
+    shutting_down: bool,
+}
+
+impl GameRoom {
+    pub(super) fn new(
+        code: String,
+        game: Game,
+        registry: Addr<WsGameServer>,
+        services: Services,
+    ) -> Self {
+        GameRoom {
+            code,
+            game,
+            registry,
+            services,
+            shutting_down: false,
+        }
+    }
+
+    fn add_player(
+        &mut self,
+        client: Client,
+        disconnect: Recipient<Disconnect>,
+        name: String,
+        player_type: PlayerType,
+        account_id: Option<String>,
+    ) -> String {
+        let game = &mut self.game;
+        let mut id = token::player_id();
+        while game.contains(&id) {
+            id = token::player_id();
+        }
+        game.players.iter().for_each(|(player_id, player)| {
+            client
+                .do_send(Message(player.joined_event(player_id).to_message()))
+                .ok();
+        });
+        let team = game.balanced_team();
+        let spawn = game.free_spawn(team);
+        game.joins += 1;
+        let player = Player {
+            account_id,
+            ..Player::new(
+                client,
+                disconnect,
+                name,
+                player_type,
+                spawn,
+                team,
+                game.joins,
+            )
+        };
+        game.players.insert(id.clone(), player);
+        id
+    }
+
+    fn add_spectator(&mut self, client: Client) -> String {
+        let game = &mut self.game;
+        let mut id = token::player_id();
+        while game.contains(&id) {
+            id = token::player_id();
+        }
+        game.players.iter().for_each(|(player_id, player)| {
+            client
+                .do_send(Message(player.joined_event(player_id).to_message()))
+                .ok();
+        });
+        game.spectators.insert(id.clone(), client);
+
+        id
+    }
+
+    fn broadcast(&mut self, msg: &str) {
+        self.record_frame(Direction::OUT, None, msg);
+        self.deliver_to_all(msg);
+    }
+
+    /// Sends to the whole room without recording, for what has no place in a replay
+    fn deliver_to_all(&self, msg: &str) {
+        for player in self.game.players.values() {
+            player.client.do_send(Message(msg.to_owned())).ok();
+        }
+        for spectator in self.game.spectators.values() {
+            spectator.do_send(Message(msg.to_owned())).ok();
+        }
+    }
+
+    /// Catches up late joiners on the conversation
+    fn send_chat_history(&self, recipient: &str) {
+        for event in self.game.chat_history.iter() {
+            self.send_message_to_player(recipient, &event.to_message());
+        }
+    }
+
+    fn broadcast_lobby_state(&mut self) {
+        let lobby_state = self.game.lobby_state().to_message();
+        self.broadcast(&lobby_state)
+    }
+
+    fn send_message_to_game(&mut self, msg: &str, src: &str) {
+        self.record_frame(Direction::OUT, None, msg);
+        self.deliver_to_game(msg, src)
+    }
+
+    /// Forwards a frame sent by a player to everyone else in the room
+    fn relay_to_game(&mut self, msg: &str, sender_id: &str) {
+        self.record_frame(Direction::IN, Some(sender_id), msg);
+        self.deliver_to_game(msg, sender_id)
+    }
+
+    fn deliver_to_game(&mut self, msg: &str, src: &str) {
+        self.game.players.retain(|player_id, player| {
+            player_id == src || player.client.do_send(Message(msg.to_owned())).is_ok()
+        });
+        self.game.spectators.retain(|spectator_id, spectator| {
+            spectator_id == src || spectator.do_send(Message(msg.to_owned())).is_ok()
+        });
+    }
+
+    fn send_message_to_player(&self, recipient: &str, msg: &str) {
+        if let Some(spectator) = self.game.spectators.get(recipient) {
+            spectator.do_send(Message(msg.to_owned())).ok();
+        }
+        if let Some(player) = self.game.players.get(recipient) {
+            player.client.do_send(Message(msg.to_owned())).ok();
+        }
+    }
+
+    /// Moves the game into the given phase, tells all players about it and
+    /// schedules the timeout of the new phase
+    fn set_phase(&mut self, phase: MatchPhase, ctx: &mut Context<Self>) {
+        let game = &mut self.game;
+        game.phase = phase;
+        game.phase_epoch += 1;
+        if phase == MatchPhase::LOBBY {
+            game.outcome = None;
+            game.winner = None;
+            for player in game.players.values_mut() {
+                player.damage_dealt = 0.;
+                player.deaths = 0;
+                player.ready = false;
+            }
+            for player in game.absent.values_mut() {
+                player.damage_dealt = 0.;
+                player.deaths = 0;
+                player.ready = false;
+            }
+        }
+        let epoch = game.phase_epoch;
+        let timeout = game.phase_timeout();
+        let timestamp = server_time();
+        if phase == MatchPhase::RUNNING {
+            game.started_at = Some(timestamp);
+        }
+        let event = game.phase_event(
+            timestamp,
+            timeout.map(|timeout| timestamp + timeout.as_millis() as u64),
+        );
+        info!("Game {} is now in phase {:?}", self.code, phase);
+        self.registry.do_send(RoomPhase {
+            code: self.code.clone(),
+            phase,
+        });
+        if phase == MatchPhase::COUNTDOWN {
+            self.start_recording(timestamp);
+        }
+        self.broadcast(&event.to_message());
+        if phase == MatchPhase::ENDED {
+            let replay_id = self.stop_recording();
+            self.record_match(timestamp, replay_id, ctx);
+        }
+        if phase == MatchPhase::LOBBY {
+            self.broadcast_lobby_state();
+        }
+
+        if let Some(timeout) = timeout {
+            ctx.run_later(timeout, move |act, ctx| {
+                act.phase_timed_out(epoch, ctx);
+            });
+        }
+    }
+
+    /// Stores the match and grants achievements on the thread pool, verifying the
+    /// replay and writing to SQLite would hold up the room
+    fn record_match(&mut self, ended_at: u64, replay_id: Option<String>, ctx: &mut Context<Self>) {
+        if self.services.store.is_none() {
+            return;
+        }
+        let game = &self.game;
+        let started_at = game.started_at.unwrap_or(ended_at);
+        let mut record = MatchRecord {
+            id: None,
+            code: self.code.clone(),
+            seed: game.map.seed,
+            mode: game.mode,
+            map_config: serde_json::json!({
+                "size": game.map.size,
+                "playerCap": game.map.player_cap,
+                "planets": game.map.planets.len(),
+            })
+            .to_string(),
+            started_at,
+            duration_ms: ended_at.saturating_sub(started_at),
+            outcome: game.outcome.unwrap_or(MatchOutcome::LOST),
+            winner: game.winner,
+            players: game
+                .players
+                .iter()
+                .map(|(player_id, player)| PlayerRecord {
+                    player_id: player_id.clone(),
+                    account_id: player.account_id.clone(),
+                    name: player.name.clone(),
+                    player_type: player.player_type.clone(),
+                    team: player.team,
+                    damage_dealt: player.damage_dealt,
+                    deaths: player.deaths,
+                    verified: false,
+                })
+                .collect(),
+        };
+        let replays = self.services.replays.clone();
+        let recorded = self.services.use_store(move |store| {
+            mark_verified(&mut record, replays.as_ref(), replay_id);
+            let id = store.record_match(&record)?;
+            info!("Recorded game {} as match {}", record.code, id);
+            achievements::grant(store, &record, ended_at)
+        });
+        let recorded = match recorded {
+            Some(recorded) => recorded,
+            None => return,
+        };
+        recorded
+            .into_actor(self)
+            .map(|unlocked, act, _ctx| match unlocked {
+                Ok(unlocked) => {
+                    for (player_id, achievement) in unlocked {
+                        info!("Player {} unlocked {:?}", player_id, achievement);
+                        act.send_message_to_player(
+                            &player_id,
+                            &AchievementUnlockedEvent {
+                                achievement,
+                                skin: achievement.skin(),
+                            }
+                            .to_message(),
+                        );
+                    }
+                }
+                Err(error) => error!("Failed to record game {}: {}", act.code, error),
+            })
+            .spawn(ctx);
+    }
+
+    /// Starts the replay with what a viewer needs to know before the countdown
+    fn start_recording(&mut self, timestamp: u64) {
+        let replays = match &self.services.replays {
+            Some(replays) => replays,
+            None => return,
+        };
+        match replays.record(&self.code, timestamp) {
+            Ok(recorder) => self.game.recorder = Some(recorder),
+            Err(error) => {
+                error!("Failed to start replay of game {}: {}", self.code, error);
+                return;
+            }
+        }
+        let game = &self.game;
+        let mut players: Vec<_> = game.players.iter().collect();
+        players.sort_by_key(|(_, player)| player.joined);
+        let frames: Vec<String> = std::iter::once(SetMapGameEvent { map: &game.map }.to_message())
+            .chain(
+                players
+                    .into_iter()
+                    .map(|(player_id, player)| player.joined_event(player_id).to_message()),
+            )
+            .collect();
+        for frame in frames {
+            self.record_frame(Direction::OUT, None, &frame);
+        }
+    }
+
+    /// Frames sent to a single player, like their leader secret, are never recorded
+    fn record_frame(&mut self, direction: Direction, player_id: Option<&str>, msg: &str) {
+        let recorded = match self.game.recorder.as_mut() {
+            Some(recorder) => recorder.record(server_time(), direction, player_id, msg),
+            None => return,
+        };
+        if let Err(error) = recorded {
+            error!("Stopped replay of game {}: {}", self.code, error);
+            self.game.recorder = None;
+        }
+    }
+
+    /// Returns the id of the saved replay
+    fn stop_recording(&mut self) -> Option<String> {
+        match self.game.recorder.take()?.finish() {
+            Ok(replay_id) => {
+                info!("Saved replay of game {} as {}", self.code, replay_id);
+                Some(replay_id)
+            }
+            Err(error) => {
+                error!("Failed to save replay of game {}: {}", self.code, error);
+                None
+            }
+        }
+    }
+
+    /// Gives up the places of restored players who did not come back in time
+    fn expire_absent(&mut self, ctx: &mut Context<Self>) {
+        if self.game.absent.is_empty() {
+            return;
+        }
+        info!(
+            "{} players did not resume game {}",
+            self.game.absent.len(),
+            self.code
+        );
+        self.game.absent.clear();
+        if self.game.players.is_empty() {
+            ctx.stop();
+            return;
+        }
+        let leader_present = match &self.game.leader {
+            Some(leader) => self.game.players.contains_key(leader),
+            None => false,
+        };
+        if !leader_present {
+            self.game.leader = None;
+            self.game.secret = None;
+            if let Some(player_id) = self.game.next_leader() {
+                self.make_player_leader(&player_id);
+            }
+        }
+        self.broadcast_lobby_state();
+    }
+
+    fn phase_timed_out(&mut self, epoch: u64, ctx: &mut Context<Self>) {
+        if self.game.phase_epoch != epoch {
+            return;
+        }
+        match self.game.phase {
+            MatchPhase::LOBBY => (),
+            MatchPhase::COUNTDOWN => {
+                let leader = self.game.leader.clone().unwrap_or_default();
+                self.set_phase(MatchPhase::RUNNING, ctx);
+                self.send_message_to_game("Event StartGame:{}", &leader);
+            }
+            MatchPhase::RUNNING => {
+                info!("Game {} ran out of time", self.code);
+                self.game.outcome = Some(MatchOutcome::LOST);
+                self.set_phase(MatchPhase::ENDED, ctx);
+            }
+            MatchPhase::ENDED => {
+                self.set_phase(MatchPhase::LOBBY, ctx);
+            }
+        }
+    }
+
+    /// Checks a leader command and tells the sender why it was refused
+    fn authorize_leader(&self, sender_id: &str, secret: Option<&str>, action: &str) -> bool {
+        let reason = match self.game.authorize(sender_id, secret) {
+            Ok(()) => return true,
+            Err(reason) => reason,
+        };
+        info!(
+            "Refused {} from {} in game {}: {}",
+            action, sender_id, self.code, reason
+        );
+        self.send_message_to_player(
+            sender_id,
+            &UnauthorizedEvent {
+                action: action.to_string(),
+                reason,
+            }
+            .to_message(),
+        );
+        false
+    }
+
+    /// Every leadership change issues a fresh secret so earlier secrets stop working
+    fn make_player_leader(&mut self, player_id: &str) {
+        let secret = token::secret();
+        self.send_message_to_player(
+            player_id,
+            &RoomLeaderEvent {
+                secret: secret.clone(),
+            }
+            .to_message(),
+        );
+        self.game.leader = Some(player_id.to_owned());
+        self.game.secret = Some(secret);
+        self.broadcast(
+            &LeaderChangedEvent {
+                player_id: player_id.to_owned(),
+            }
+            .to_message(),
+        );
+    }
+
+    fn joined(&self, player_id: String, ctx: &mut Context<Self>) -> Joined {
+        Joined {
+            player_id,
+            game_name: self.code.clone(),
+            room: ctx.address(),
+        }
+    }
+
+    fn refuse_skin(&self, sender_id: &str, skin: Skin) {
+        self.send_message_to_player(
+            sender_id,
+            &UnauthorizedEvent {
+                action: "ChangeSkin".to_string(),
+                reason: format!("{:?} has not been unlocked", skin),
+            }
+            .to_message(),
+        );
+    }
+
+    /// The match may have started while the achievements were looked up
+    fn change_skin(&mut self, sender_id: String, skin: Option<Skin>) {
+        if self.game.phase != MatchPhase::LOBBY {
+            return;
+        }
+        match self.game.players.get_mut(&sender_id) {
+            Some(player) => player.skin = skin,
+            None => return,
+        }
+        self.broadcast(
+            &SkinChangedEvent {
+                player_id: sender_id,
+                skin,
+            }
+            .to_message(),
+        );
+        self.broadcast_lobby_state();
+    }
+}
+
+/// Marks the players whose part of the replay checked out. Without a replay nobody can
+/// be vouched for, and a divergence nobody in particular caused taints the whole match.
+fn mark_verified(record: &mut MatchRecord, replays: Option<&Replays>, replay_id: Option<String>) {
+    let replay = match (replays, replay_id) {
+        (Some(replays), Some(replay_id)) => match replays.load(&replay_id) {
+            Ok(replay) => replay,
+            Err(error) => {
+                error!("Failed to verify replay {}: {}", replay_id, error);
+                return;
+            }
+        },
+        _ => return,
+    };
+    let mut diverged = HashSet::new();
+    for divergence in replay::verify(&replay) {
+        warn!("Replay of game {} diverges: {}", record.code, divergence);
+        match divergence.player_id {
+            Some(player_id) => diverged.insert(player_id),
+            None => return,
+        };
+    }
+    for player in &mut record.players {
+        player.verified = !diverged.contains(&player.player_id);
+    }
+}
+
+impl Actor for GameRoom {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.subscribe_system_async::<ServerShutdown>(ctx);
+
+        // restored results are shown for as long as usual
+        if self.game.phase == MatchPhase::ENDED {
+            let epoch = self.game.phase_epoch;
+            ctx.run_later(Game::RESULTS, move |act, ctx| {
+                act.phase_timed_out(epoch, ctx);
+            });
+        }
+        if !self.game.absent.is_empty() {
+            ctx.run_later(WsGameServer::RESUME_WINDOW, |act, ctx| {
+                act.expire_absent(ctx)
+            });
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.stop_recording();
+        self.registry.do_send(RoomClosed {
+            code: self.code.clone(),
+        });
+    }
+}
+
+impl Handler<JoinGame> for GameRoom {
+    type Result = Result<Joined, String>;
+
+    fn handle(&mut self, msg: JoinGame, ctx: &mut Self::Context) -> Self::Result {
+        let JoinGame {
+            game_name: _,
+            player,
+            disconnect,
+            spectator,
+            name,
+            account_name,
+            player_type,
+            account_id,
+        } = msg;
+        if self.game.locked {
+            return Err("game is locked".to_string());
+        }
+
+        if spectator {
+            let id = self.add_spectator(player);
+            self.send_message_to_player(
+                &id,
+                &JoinedGame {
+                    ok: true,
+                    reason: None,
+                    code: Some(self.code.clone()),
+                    name: None,
+                    player_type: None,
+                    spawn: None,
+                    team: None,
+                    spectator: true,
+                    resume_token: None,
+                }
+                .to_message(),
+            );
+            self.send_message_to_player(
+                &id,
+                &SetMapGameEvent {
+                    map: &self.game.map,
+                }
+                .to_message(),
+            );
+            self.send_chat_history(&id);
+            self.broadcast_lobby_state();
+            return Ok(self.joined(id, ctx));
+        }
+
+        let game = &self.game;
+        if game.phase != MatchPhase::LOBBY {
+            return Err("game is running".to_string());
+        }
+        if game.players.len() + game.absent.len() >= game.max_players {
+            return Err("game is full".to_string());
+        }
+        let name = game.display_name(name, account_name)?;
+        let player_type = game
+            .free_player_type(player_type)
+            .ok_or_else(|| "game is full".to_string())?;
+        let id = self.add_player(player, disconnect, name, player_type, account_id);
+        let joined = self.game.players[&id].joined_event(&id);
+        let resume_token = self.game.players[&id].resume_token.clone();
+
+        self.send_message_to_player(
+            &id,
+            &JoinedGame {
+                ok: true,
+                reason: None,
+                code: Some(self.code.clone()),
+                name: Some(joined.name.clone()),
+                player_type: Some(joined.player_type.clone()),
+                spawn: Some(joined.spawn.clone()),
+                team: Some(joined.team),
+                spectator: false,
+                resume_token: Some(resume_token),
+            }
+            .to_message(),
+        );
+        self.send_message_to_player(
+            &id,
+            &SetMapGameEvent {
+                map: &self.game.map,
+            }
+            .to_message(),
+        );
+        self.send_chat_history(&id);
+        if self.game.leader.is_none() {
+            info!("Making {} leader of game {}", id, self.code);
+            self.make_player_leader(&id);
+        }
+        self.send_message_to_game(&joined.to_message(), &id);
+        self.broadcast_lobby_state();
+        Ok(self.joined(id, ctx))
+    }
+}
+
+impl Handler<ResumeGame> for GameRoom {
+    type Result = Result<Joined, String>;
+
+    fn handle(&mut self, msg: ResumeGame, ctx: &mut Self::Context) -> Self::Result {
+        let ResumeGame {
+            game_name: _,
+            resume_token,
+            player,
+            disconnect,
+        } = msg;
+        let game = &mut self.game;
+        let snapshot = game.take_absent(&resume_token)?;
+        let id = snapshot.player_id.clone();
+        game.players.iter().for_each(|(player_id, present)| {
+            player
+                .do_send(Message(present.joined_event(player_id).to_message()))
+                .ok();
+        });
+        game.players
+            .insert(id.clone(), Player::restore(snapshot, player, disconnect));
+        info!("Player {} resumed game {}", id, self.code);
+
+        let resumed = &self.game.players[&id];
+        let joined = resumed.joined_event(&id);
+        self.send_message_to_player(
+            &id,
+            &JoinedGame {
+                ok: true,
+                reason: None,
+                code: Some(self.code.clone()),
+                name: Some(joined.name.clone()),
+                player_type: Some(joined.player_type.clone()),
+                spawn: Some(joined.spawn.clone()),
+                team: Some(joined.team),
+                spectator: false,
+                resume_token: Some(resumed.resume_token.clone()),
+            }
+            .to_message(),
+        );
+        self.send_message_to_player(
+            &id,
+            &SetMapGameEvent {
+                map: &self.game.map,
+            }
+            .to_message(),
+        );
+        if self.game.phase == MatchPhase::ENDED {
+            self.send_message_to_player(
+                &id,
+                &self.game.phase_event(server_time(), None).to_message(),
+            );
+        }
+        if self.game.leader.is_none() || self.game.leader.as_deref() == Some(&id) {
+            self.make_player_leader(&id);
+        }
+        self.send_message_to_game(&joined.to_message(), &id);
+        self.broadcast_lobby_state();
+        Ok(self.joined(id, ctx))
+    }
+}
+
+impl Handler<LeaveGame> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveGame, ctx: &mut Self::Context) {
+        let game = &mut self.game;
+        if game.spectators.remove(&msg.player_id).is_some() {
+            self.broadcast_lobby_state();
+            return;
+        }
+        if game.players.remove(&msg.player_id).is_none() {
+            return;
+        }
+        let mut new_lead: Option<String> = None;
+        if game.leader == Some(msg.player_id.clone()) {
+            if game.players.is_empty() && game.absent.is_empty() {
+                ctx.stop();
+                return;
+            }
+
+            // the secret of the leaving player must not be usable anymore
+            game.leader = None;
+            game.secret = None;
+            new_lead = game.next_leader();
+        }
+        info!("Removing {} from game {:?}", msg.player_id, self.code);
+        self.send_message_to_game(
+            &PlayerLeftGameEvent {
+                player_id: msg.player_id.clone(),
+            }
+            .to_message(),
+            &msg.player_id,
+        );
+        if let Some(player_id) = new_lead {
+            self.make_player_leader(&player_id);
+        }
+        self.broadcast_lobby_state();
+    }
+}
+
+impl Handler<GameState> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: GameState, _ctx: &mut Self::Context) {
+        let GameState {
+            game_name: _,
+            sender_id,
+            secret,
+            payload,
+        } = msg;
+        if !self.authorize_leader(&sender_id, secret.as_deref(), "GameState") {
+            return;
+        }
+        self.relay_to_game(&GameStateEvent { payload }.to_message(), &sender_id);
+    }
+}
+
+impl Handler<StartGame> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: StartGame, ctx: &mut Self::Context) {
+        let StartGame {
+            secret,
+            sender_id,
+            game_name: _,
+            force,
+        } = msg;
+        if !self.authorize_leader(&sender_id, secret.as_deref(), "StartGame") {
+            return;
+        }
+        if self.game.phase != MatchPhase::LOBBY {
+            return;
+        }
+        if self.shutting_down {
+            self.send_message_to_player(
+                &sender_id,
+                &StartGameRefusedEvent {
+                    reason: "server is shutting down".to_string(),
+                }
+                .to_message(),
+            );
+            return;
+        }
+        let not_ready = self.game.not_ready();
+        if not_ready > 0 && !force {
+            self.send_message_to_player(
+                &sender_id,
+                &StartGameRefusedEvent {
+                    reason: format!("waiting for {} players to get ready", not_ready),
+                }
+                .to_message(),
+            );
+            return;
+        }
+        self.set_phase(MatchPhase::COUNTDOWN, ctx);
+    }
+}
+
+impl Handler<SetReady> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetReady, _ctx: &mut Self::Context) {
+        if self.game.phase != MatchPhase::LOBBY {
+            return;
+        }
+        match self.game.players.get_mut(&msg.sender_id) {
+            Some(player) if player.ready != msg.ready => player.ready = msg.ready,
+            _ => return,
+        }
+        self.broadcast_lobby_state();
+    }
+}
+
+impl Handler<ChangeColor> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChangeColor, _ctx: &mut Self::Context) {
+        let ChangeColor {
+            sender_id,
+            game_name: _,
+            player_type,
+        } = msg;
+        let game = &mut self.game;
+        if game.phase != MatchPhase::LOBBY
+            || game.free_player_type(Some(player_type.clone())) != Some(player_type.clone())
+        {
+            return;
+        }
+        match game.players.get_mut(&sender_id) {
+            Some(player) => player.player_type = player_type.clone(),
+            None => return,
+        }
+        self.broadcast(
+            &ColorChangedEvent {
+                player_id: sender_id,
+                player_type,
+            }
+            .to_message(),
+        );
+        self.broadcast_lobby_state();
+    }
+}
+
+impl Handler<ChangeSkin> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChangeSkin, ctx: &mut Self::Context) {
+        let ChangeSkin {
+            sender_id,
+            game_name: _,
+            skin,
+        } = msg;
+        if self.game.phase != MatchPhase::LOBBY {
+            return;
+        }
+        let account_id = match self.game.players.get(&sender_id) {
+            Some(player) => player.account_id.clone(),
+            None => return,
+        };
+        let (skin, account_id) = match (skin, account_id) {
+            (None, _) => return self.change_skin(sender_id, None),
+            (Some(skin), Some(account_id)) => (skin, account_id),
+            (Some(skin), None) => return self.refuse_skin(&sender_id, skin),
+        };
+        let unlocked = self
+            .services
+            .use_store(move |store| store.achievements(&account_id));
+        let unlocked = match unlocked {
+            Some(unlocked) => unlocked,
+            None => return self.refuse_skin(&sender_id, skin),
+        };
+        unlocked
+            .into_actor(self)
+            .map(move |unlocked, act, _ctx| match unlocked {
+                Ok(unlocked) if achievements::owns_skin(&unlocked, skin) => {
+                    act.change_skin(sender_id, Some(skin))
+                }
+                Ok(_) => act.refuse_skin(&sender_id, skin),
+                Err(error) => {
+                    error!("Failed to load achievements of {}: {}", sender_id, error);
+                    act.refuse_skin(&sender_id, skin)
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Handler<Rematch> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Rematch, ctx: &mut Self::Context) {
+        let Rematch {
+            secret,
+            sender_id,
+            game_name: _,
+            same_seed,
+        } = msg;
+        if !self.authorize_leader(&sender_id, secret.as_deref(), "Rematch") {
+            return;
+        }
+        let game = &mut self.game;
+        if game.phase != MatchPhase::ENDED && game.phase != MatchPhase::LOBBY {
+            return;
+        }
+        game.map = if same_seed {
+            GameMap::create_from_seed(game.mode, game.map.seed)
+        } else {
+            GameMap::create_random(game.mode)
+        };
+        game.reassign_spawns();
+        info!("Rematch in game {} on map {}", self.code, game.map.seed);
+        self.set_phase(MatchPhase::LOBBY, ctx);
+        let spawns = SpawnsEvent {
+            spawns: self
+                .game
+                .players
+                .iter()
+                .map(|(player_id, player)| PlayerSpawn {
+                    player_id: player_id.clone(),
+                    team: player.team,
+                    spawn: player.spawn.clone(),
+                })
+                .collect(),
+        };
+        let map = SetMapGameEvent {
+            map: &self.game.map,
+        }
+        .to_message();
+        self.broadcast(&map);
+        self.broadcast(&spawns.to_message());
+    }
+}
+
+impl Handler<SetTeam> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetTeam, _ctx: &mut Self::Context) {
+        let SetTeam {
+            secret,
+            sender_id,
+            game_name: _,
+            player_id,
+            team,
+        } = msg;
+        if !self.authorize_leader(&sender_id, secret.as_deref(), "SetTeam") {
+            return;
+        }
+        let game = &mut self.game;
+        if game.phase != MatchPhase::LOBBY || game.mode != GameMode::RACE {
+            return;
+        }
+        if game.team_size(team) >= game.map.spawns_for(team).len() {
+            return;
+        }
+        let spawn = game.free_spawn(team);
+        match game.players.get_mut(&player_id) {
+            Some(player) if player.team != team => {
+                player.team = team;
+                player.spawn = spawn.clone();
+            }
+            _ => return,
+        }
+        self.broadcast(
+            &TeamChangedEvent {
+                player_id,
+                team,
+                spawn,
+            }
+            .to_message(),
+        );
+        self.broadcast_lobby_state();
+    }
+}
+
+impl Handler<KickPlayer> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: KickPlayer, ctx: &mut Self::Context) {
+        let KickPlayer {
+            secret,
+            sender_id,
+            game_name,
+            player_id,
+            reason,
+        } = msg;
+        if !self.authorize_leader(&sender_id, secret.as_deref(), "KickPlayer") {
+            return;
+        }
+        if sender_id == player_id {
+            return;
+        }
+        let disconnect = match self.game.players.get(&player_id) {
+            Some(player) => player.disconnect.clone(),
+            None => return,
+        };
+        info!("Kicking {} from game {}: {}", player_id, self.code, reason);
+        self.send_message_to_player(
+            &player_id,
+            &KickedEvent {
+                reason: reason.clone(),
+            }
+            .to_message(),
+        );
+        self.handle(
+            LeaveGame {
+                game_name,
+                player_id,
+            },
+            ctx,
+        );
+        disconnect.do_send(Disconnect { reason }).ok();
+    }
+}
+
+impl Handler<TransferLeadership> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: TransferLeadership, _ctx: &mut Self::Context) {
+        let TransferLeadership {
+            secret,
+            sender_id,
+            game_name: _,
+            player_id,
+        } = msg;
+        if !self.authorize_leader(&sender_id, secret.as_deref(), "TransferLeadership") {
+            return;
+        }
+        if sender_id == player_id || !self.game.players.contains_key(&player_id) {
+            return;
+        }
+        info!(
+            "{} hands leadership of game {} to {}",
+            sender_id, self.code, player_id
+        );
+        self.make_player_leader(&player_id);
+        self.broadcast_lobby_state();
+    }
+}
+
+impl Handler<LockRoom> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: LockRoom, _ctx: &mut Self::Context) {
+        if !self.authorize_leader(&msg.sender_id, msg.secret.as_deref(), "LockRoom") {
+            return;
+        }
+        self.game.locked = msg.locked;
+        self.broadcast_lobby_state();
+    }
+}
+
+impl Handler<SetMaxPlayers> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetMaxPlayers, _ctx: &mut Self::Context) {
+        if !self.authorize_leader(&msg.sender_id, msg.secret.as_deref(), "SetMaxPlayers") {
+            return;
+        }
+        let game = &mut self.game;
+        game.max_players = msg
+            .max_players
+            .max(game.players.len())
+            .max(1)
+            .min(game.map.player_cap);
+        self.broadcast_lobby_state();
+    }
+}
+
+impl Handler<Chat> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Chat, _ctx: &mut Self::Context) {
+        let Chat {
+            game_name: _,
+            sender_id,
+            message,
+        } = msg;
+        let player = match self.game.players.get_mut(&sender_id) {
+            Some(player) => player,
+            None => return,
+        };
+        let name = player.name.clone();
+        let checked = if player.muted {
+            Err("you are muted".to_string())
+        } else if !player.chat_limiter.try_take() {
+            Err("you are sending messages too fast".to_string())
+        } else {
+            chat::validate_message(&message).and_then(|message| match &self.services.chat_filter {
+                Some(filter) => filter.filter(&message),
+                None => Ok(message),
+            })
+        };
+        let message = match checked {
+            Ok(message) => message,
+            Err(reason) => {
+                self.send_message_to_player(&sender_id, &ChatRejectedEvent { reason }.to_message());
+                return;
+            }
+        };
+        let event = ChatEvent {
+            player_id: sender_id,
+            name,
+            message,
+            timestamp: server_time(),
+        };
+        let history = &mut self.game.chat_history;
+        if history.len() == chat::HISTORY_LENGTH {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        // replays may be watched by people who were not in the room
+        self.deliver_to_all(&event.to_message());
+    }
+}
+
+impl Handler<PlaceMarker> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlaceMarker, _ctx: &mut Self::Context) {
+        const MARKER_TTL: Duration = Duration::from_secs(8);
+        let PlaceMarker {
+            game_name: _,
+            sender_id,
+            kind,
+            position,
+        } = msg;
+        let game = &mut self.game;
+        if position.x > game.map.size.x || position.y > game.map.size.y {
+            return;
+        }
+        let allowed = match game.players.get_mut(&sender_id) {
+            Some(player) => player.try_quick_ping(),
+            None => false,
+        };
+        if !allowed {
+            return;
+        }
+        self.send_message_to_game(
+            &MarkerEvent {
+                player_id: sender_id.clone(),
+                kind,
+                position,
+                ttl: MARKER_TTL.as_millis() as u64,
+            }
+            .to_message(),
+            &sender_id,
+        );
+    }
+}
+
+impl Handler<SendEmote> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendEmote, _ctx: &mut Self::Context) {
+        const EMOTE_TTL: Duration = Duration::from_secs(3);
+        let SendEmote {
+            game_name: _,
+            sender_id,
+            emote,
+        } = msg;
+        let allowed = match self.game.players.get_mut(&sender_id) {
+            Some(player) => player.try_quick_ping(),
+            None => false,
+        };
+        if !allowed {
+            return;
+        }
+        self.send_message_to_game(
+            &EmoteEvent {
+                player_id: sender_id.clone(),
+                emote,
+                ttl: EMOTE_TTL.as_millis() as u64,
+            }
+            .to_message(),
+            &sender_id,
+        );
+    }
+}
+
+impl Handler<MutePlayer> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: MutePlayer, _ctx: &mut Self::Context) {
+        let MutePlayer {
+            secret,
+            sender_id,
+            game_name: _,
+            player_id,
+            muted,
+        } = msg;
+        if !self.authorize_leader(&sender_id, secret.as_deref(), "MutePlayer") {
+            return;
+        }
+        if let Some(player) = self.game.players.get_mut(&player_id) {
+            info!(
+                "Setting muted of {} in game {} to {}",
+                player_id, self.code, muted
+            );
+            player.muted = muted;
+        }
+    }
+}
+
+impl Handler<ReportLatency> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReportLatency, _ctx: &mut Self::Context) {
+        if let Some(player) = self.game.players.get_mut(&msg.sender_id) {
+            player.latency = Some(msg.latency);
+        }
+    }
+}
+
+impl Handler<PlayerState> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayerState, ctx: &mut Self::Context) {
+        let PlayerState {
+            game_name: _,
+            sender_id,
+            mut state,
+            damage,
+            target,
+        } = msg;
+        let game = &mut self.game;
+        let running = game.phase == MatchPhase::RUNNING;
+        let player = match game.players.get_mut(&sender_id) {
+            Some(player) => player,
+            None => return,
+        };
+        let team = player.team;
+        let counted = if running && damage > 0. && target.unwrap_or(team) == team {
+            player.count_damage(damage)
+        } else {
+            0.
+        };
+        if running && counted < damage {
+            warn!(
+                "Counted {} of the {} damage {} reported in game {}",
+                counted, damage, sender_id, self.code
+            );
+        }
+        player.damage_dealt += counted;
+        if state.contains_key("damageDealt") {
+            state.insert("damageDealt".to_string(), serde_json::json!(counted));
+        }
+        // relayed before the match can end, so the state which destroys the enemy planet
+        // precedes the end of the match
+        self.relay_to_game(
+            &format!("Event PlayerState:{}", serde_json::Value::Object(state)),
+            &sender_id,
+        );
+        let game = &mut self.game;
+        if counted > 0. && game.team_damage(team) >= GameMap::ENEMY_PLANET_HEALTH {
+            info!(
+                "Team {:?} destroyed its enemy planet in game {}",
+                team, self.code
+            );
+            game.outcome = Some(MatchOutcome::WON);
+            if game.mode == GameMode::RACE {
+                game.winner = Some(team);
+            }
+            self.set_phase(MatchPhase::ENDED, ctx);
+        }
+    }
+}
+
+impl Handler<PlayerDied> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayerDied, _ctx: &mut Self::Context) {
+        if self.game.phase != MatchPhase::RUNNING {
+            return;
+        }
+        if let Some(player) = self.game.players.get_mut(&msg.sender_id) {
+            player.deaths += 1;
+        }
+    }
+}
+
+impl Handler<GameMessage> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: GameMessage, _ctx: &mut Self::Context) {
+        if self.game.players.contains_key(&msg.sender_id) {
+            self.relay_to_game(&msg.message, &msg.sender_id);
+        }
+    }
+}
+
+impl Handler<ServerShutdown> for GameRoom {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ServerShutdown, _ctx: &mut Self::Context) {
+        self.shutting_down = true;
+    }
+}
+
+impl Handler<CloseRoom> for GameRoom {
+    type Result = MessageResult<CloseRoom>;
+
+    fn handle(&mut self, _msg: CloseRoom, ctx: &mut Self::Context) -> Self::Result {
+        self.stop_recording();
+        ctx.stop();
+        let game = &self.game;
+        if game.players.is_empty() && game.absent.is_empty() {
+            return MessageResult(None);
+        }
+        MessageResult(Some(game.snapshot(&self.code)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::Sink;
+
+    /// Adds a player the way a join would, needs a running `System`
+    fn join<'a>(game: &'a mut Game, id: &str, name: &str) -> &'a mut Player {
+        let sink = Sink.start();
+        let team = game.balanced_team();
+        game.joins += 1;
+        let player = Player::new(
+            sink.clone().recipient(),
+            sink.recipient(),
+            name.to_string(),
+            game.free_player_type(None).expect("Failed to get a colour"),
+            game.free_spawn(team),
+            team,
+            game.joins,
+        );
+        game.players.insert(id.to_string(), player);
+        game.players.get_mut(id).expect("Failed to get player")
+    }
+
+    #[test]
+    fn falls_back_to_a_free_pilot_name() {
+        let _system = System::new("test");
+        let mut game = Game::new(GameMode::COOP, None);
+        join(&mut game, "a", "Maverick");
+
+        assert_eq!(
+            game.display_name(None, Some("maverick".to_string())),
+            Ok("Pilot 1".to_string())
+        );
+        assert_eq!(
+            game.display_name(None, Some("Goose".to_string())),
+            Ok("Goose".to_string())
+        );
+        assert!(game
+            .display_name(Some("MAVERICK".to_string()), None)
+            .is_err());
+    }
+
+    #[test]
+    fn keeps_the_place_of_a_player_who_could_not_resume() {
+        let _system = System::new("test");
+        let mut game = Game::new(GameMode::COOP, None);
+        let snapshot = join(&mut game, "a", "Maverick").snapshot("a");
+        game.players.remove("a");
+        game.absent.insert("a".to_string(), snapshot.clone());
+        for (index, _) in PlayerType::ALL.iter().enumerate() {
+            join(&mut game, &index.to_string(), "Maverick");
+        }
+
+        assert!(game.take_absent("wrong token").is_err());
+        assert_eq!(
+            game.take_absent(&snapshot.resume_token).err(),
+            Some("game is full".to_string())
+        );
+        assert!(game.absent.contains_key("a"));
+
+        game.players.remove("0");
+        game.phase = MatchPhase::RUNNING;
+        assert_eq!(
+            game.take_absent(&snapshot.resume_token).err(),
+            Some("game is running".to_string())
+        );
+        assert!(game.absent.contains_key("a"));
+
+        game.phase = MatchPhase::ENDED;
+        let resumed = game.take_absent(&snapshot.resume_token).unwrap();
+        assert_eq!(resumed.name, "Pilot 1");
+        assert!(game.absent.is_empty());
+    }
+
+    #[test]
+    fn the_leader_does_not_need_to_ready_up() {
+        let _system = System::new("test");
+        let mut game = Game::new(GameMode::COOP, None);
+        join(&mut game, "a", "Maverick");
+        join(&mut game, "b", "Goose");
+        join(&mut game, "c", "Iceman");
+        game.leader = Some("a".to_string());
+        assert_eq!(game.not_ready(), 2);
+
+        game.players.get_mut("b").unwrap().ready = true;
+        assert_eq!(game.not_ready(), 1);
+        game.players.get_mut("c").unwrap().ready = true;
+        assert_eq!(game.not_ready(), 0);
+    }
+
+    #[test]
+    fn only_the_leader_is_authorized() {
+        let _system = System::new("test");
+        let mut game = Game::new(GameMode::COOP, None);
+        join(&mut game, "a", "Maverick");
+        join(&mut game, "b", "Goose");
+        assert!(game.authorize("a", None).is_err());
+
+        game.leader = Some("a".to_string());
+        game.secret = Some("secret".to_string());
+        assert!(game.authorize("a", None).is_ok());
+        assert!(game.authorize("a", Some("secret")).is_ok());
+        assert!(game.authorize("a", Some("guess")).is_err());
+        assert!(game.authorize("b", Some("secret")).is_err());
+    }
+
+    #[test]
+    fn picks_the_best_connection_as_next_leader() {
+        let _system = System::new("test");
+        let mut game = Game::new(GameMode::COOP, None);
+        assert_eq!(game.next_leader(), None);
+        join(&mut game, "a", "Maverick");
+        join(&mut game, "b", "Goose").latency = Some(Duration::from_millis(120));
+        join(&mut game, "c", "Iceman").latency = Some(Duration::from_millis(30));
+        join(&mut game, "d", "Viper").latency = Some(Duration::from_millis(10));
+        // c and d share a bucket, so the earlier join wins
+        assert_eq!(game.next_leader(), Some("c".to_string()));
+
+        game.players.remove("c");
+        assert_eq!(game.next_leader(), Some("d".to_string()));
+        game.players.remove("d");
+        game.players.remove("b");
+        assert_eq!(game.next_leader(), Some("a".to_string()));
+    }
+
+    #[test]
+    fn quick_pings_have_a_cooldown() {
+        let _system = System::new("test");
+        let mut game = Game::new(GameMode::COOP, None);
+        let player = join(&mut game, "a", "Maverick");
+        assert!(player.try_quick_ping());
+        assert!(!player.try_quick_ping());
+
+        player.last_quick_ping = Some(Instant::now() - Player::QUICK_PING_COOLDOWN);
+        assert!(player.try_quick_ping());
+        assert!(!player.try_quick_ping());
+    }
+
+    #[test]
+    fn falls_back_to_the_first_free_colour() {
+        let _system = System::new("test");
+        let mut game = Game::new(GameMode::COOP, None);
+        assert_eq!(
+            game.free_player_type(Some(PlayerType::RED)),
+            Some(PlayerType::RED)
+        );
+        join(&mut game, "a", "Maverick").player_type = PlayerType::RED;
+        assert_eq!(
+            game.free_player_type(Some(PlayerType::RED)),
+            Some(PlayerType::ALL[0].clone())
+        );
+
+        for (index, _) in PlayerType::ALL.iter().enumerate().skip(1) {
+            join(&mut game, &index.to_string(), "Goose");
+        }
+        assert_eq!(game.free_player_type(None), None);
+    }
+}
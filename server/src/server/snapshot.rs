@@ -0,0 +1,137 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::achievements::Skin;
+use crate::server::events::PlayerType;
+use crate::server::game_objects::{Coordinates, GameMode, MatchOutcome, MatchPhase, Team};
+
+/// Bumped whenever the layout of a snapshot changes
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The rooms of a server which shut down, so that the next one can take them over
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub version: u32,
+    /// Server time in ms at which the snapshot was written
+    pub saved_at: u64,
+    pub rooms: Vec<RoomSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSnapshot {
+    pub code: String,
+    pub mode: GameMode,
+    /// The map is generated again from its seed
+    pub seed: u32,
+    pub duration_ms: u64,
+    pub locked: bool,
+    pub max_players: usize,
+    pub phase: MatchPhase,
+    pub leader: Option<String>,
+    pub joins: u64,
+    pub outcome: Option<MatchOutcome>,
+    pub winner: Option<Team>,
+    pub players: Vec<PlayerSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerSnapshot {
+    pub player_id: String,
+    /// Lets the client of the player take its place again after a restart
+    pub resume_token: String,
+    pub account_id: Option<String>,
+    pub name: String,
+    pub player_type: PlayerType,
+    pub skin: Option<Skin>,
+    pub spawn: Coordinates,
+    pub team: Team,
+    pub damage_dealt: f64,
+    pub deaths: u32,
+    pub ready: bool,
+    pub joined: u64,
+}
+
+impl Snapshot {
+    pub fn new(saved_at: u64, rooms: Vec<RoomSnapshot>) -> Self {
+        Snapshot {
+            version: FORMAT_VERSION,
+            saved_at,
+            rooms,
+        }
+    }
+
+    /// Writes to a temporary file first, so a crash never leaves half a snapshot behind
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_vec(self).map_err(|error| error.to_string())?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json).map_err(|error| error.to_string())?;
+        fs::rename(&tmp, path).map_err(|error| error.to_string())
+    }
+
+    /// Reads and removes the snapshot, so that its rooms are restored at most once
+    pub fn take(path: &Path) -> Result<Option<Self>, String> {
+        let json = match fs::read(path) {
+            Ok(json) => json,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.to_string()),
+        };
+        fs::remove_file(path).map_err(|error| error.to_string())?;
+        let snapshot: Snapshot = serde_json::from_slice(&json)
+            .map_err(|error| format!("invalid snapshot: {}", error))?;
+        if snapshot.version != FORMAT_VERSION {
+            return Err(format!("unsupported snapshot version {}", snapshot.version));
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn saves_and_takes_snapshots() {
+        let path = std::env::temp_dir().join(format!("rooms-{}.json", crate::token::player_id()));
+        assert_eq!(Snapshot::take(&path).unwrap(), None);
+
+        let snapshot = Snapshot::new(
+            1000,
+            vec![RoomSnapshot {
+                code: "ACDEF".to_string(),
+                mode: GameMode::RACE,
+                seed: 42,
+                duration_ms: 60_000,
+                locked: true,
+                max_players: 4,
+                phase: MatchPhase::ENDED,
+                leader: Some("a".to_string()),
+                joins: 1,
+                outcome: Some(MatchOutcome::WON),
+                winner: Some(Team::BETA),
+                players: vec![PlayerSnapshot {
+                    player_id: "a".to_string(),
+                    resume_token: "secret".to_string(),
+                    account_id: None,
+                    name: "Pilot 1".to_string(),
+                    player_type: PlayerType::RED,
+                    skin: Some(Skin::GHOST),
+                    spawn: Coordinates { x: 1, y: 2 },
+                    team: Team::BETA,
+                    damage_dealt: 25.,
+                    deaths: 1,
+                    ready: true,
+                    joined: 1,
+                }],
+            }],
+        );
+        snapshot.save(&path).unwrap();
+        assert_eq!(Snapshot::take(&path).unwrap(), Some(snapshot));
+        assert!(!path.exists());
+    }
+}
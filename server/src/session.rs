@@ -1,20 +1,62 @@
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use actix::fut;
 use actix::prelude::*;
-use actix_broker::BrokerIssue;
+use actix_broker::BrokerSubscribe;
 use actix_web_actors::ws;
 
 use serde_json::json;
 
-use crate::message::{CreateGame, GameMessage, GameState, JoinGame, LeaveGame, Message, StartGame};
-use crate::server::WsGameServer;
+use crate::accounts::AccountInfo;
+use crate::achievements::{Achievement, Skin};
+use crate::connection_limit::ConnectionLimiter;
+use crate::inbound::{EventRateLimiter, InboundLimits};
+use crate::message::{
+    ChangeColor, ChangeSkin, Chat, CloseConnection, CloseSessions, CreateGame, Disconnect,
+    GameMessage, GameState, GetAchievements, GetLeaderboard, InvalidCode, JoinAttempt, JoinGame,
+    Joined, KickPlayer, LeaveGame, LockRoom, Message, MutePlayer, PlaceMarker, PlayerDied,
+    PlayerState, Rematch, ReportLatency, ResumeGame, SendEmote, ServerShutdown, SetMaxPlayers,
+    SetReady, SetTeam, StartGame, TransferLeadership,
+};
+use crate::rate_limit::TokenBucket;
+use crate::server::{
+    server_time, Coordinates, Emote, GameMode, GameRoom, MarkerKind, PlayerType, Team, WsGameServer,
+};
+use crate::storage::LeaderboardQuery;
 use std::time::{Duration, Instant};
 
-#[derive(Default)]
+/// Reasons may contain anything, like a rejected display name
+fn join_failed(reason: &str) -> String {
+    format!(
+        "Event JoinGame:{}",
+        json!({ "ok": false, "reason": reason })
+    )
+}
+
+/// A ready event without a flag marks the player ready
+fn ready_flag(payload: Option<&serde_json::Map<String, serde_json::Value>>) -> bool {
+    payload
+        .and_then(|json| json.get("ready"))
+        .and_then(|ready| ready.as_bool())
+        .unwrap_or(true)
+}
+
 pub struct PlayerSession {
     id: String,
     game_name: Option<String>,
+    /// The room of `game_name`, events of the player are sent straight to it
+    room: Option<Addr<GameRoom>>,
+    /// Remote address of the client, behind a proxy taken from its forwarding headers
+    ip: String,
+    /// Set if the client opened the websocket with a valid account token, guests have none
+    account: Option<AccountInfo>,
+    join_limiter: TokenBucket,
+    limits: InboundLimits,
+    event_limiter: EventRateLimiter,
+    /// Every limit violation costs a strike, a session without strikes left is dropped
+    strikes: TokenBucket,
+    /// Last `dead` flag of the player state, deaths are counted when it turns true
+    dead: bool,
     /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
     /// otherwise we drop connection.
     hb: HeartBeat,
@@ -22,6 +64,9 @@ pub struct PlayerSession {
 
 struct HeartBeat {
     last_client_hb: Instant,
+    last_ping: Instant,
+    /// Smoothed round trip time of our websocket pings
+    latency: Option<Duration>,
     interval: Duration,
     timeout: Duration,
 }
@@ -30,47 +75,129 @@ impl Default for HeartBeat {
     fn default() -> Self {
         HeartBeat {
             last_client_hb: Instant::now(),
+            last_ping: Instant::now(),
+            latency: None,
             interval: Duration::from_secs(5),
             timeout: Duration::from_secs(10),
         }
     }
 }
 
+impl HeartBeat {
+    fn pong(&mut self) -> Duration {
+        self.last_client_hb = Instant::now();
+        let round_trip = self.last_client_hb.duration_since(self.last_ping);
+        let latency = match self.latency {
+            Some(latency) => (latency * 3 + round_trip) / 4,
+            None => round_trip,
+        };
+        self.latency = Some(latency);
+
+        latency
+    }
+}
+
 impl PlayerSession {
-    pub fn join_game(&mut self, game_name: &str, ctx: &mut ws::WebsocketContext<Self>) {
-        let game_name = game_name.to_owned();
+    pub fn new(ip: String, limits: InboundLimits, account: Option<AccountInfo>) -> Self {
+        PlayerSession {
+            id: String::default(),
+            game_name: None,
+            room: None,
+            ip,
+            account,
+            join_limiter: TokenBucket::new(5, Duration::from_secs(2)),
+            limits,
+            event_limiter: EventRateLimiter::new(limits),
+            strikes: TokenBucket::new(3, Duration::from_secs(30)),
+            dead: false,
+            hb: HeartBeat::default(),
+        }
+    }
 
-        match &self.game_name {
-            Some(game_name) => {
-                let leave_msg = LeaveGame {
-                    game_name: game_name.clone(),
-                    player_id: self.id.clone(),
-                };
-                self.issue_system_sync(leave_msg, ctx);
-            }
-            _ => (),
+    fn account_name(&self) -> Option<String> {
+        self.account
+            .as_ref()
+            .map(|account| account.username.clone())
+    }
+
+    /// Discards the offending frame with a warning, or drops the client once it used up its strikes
+    fn violation(&mut self, reason: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.strikes.try_take() {
+            warn!("Warning session {} ({}): {}", self.id, self.ip, reason);
+            ctx.text(format!("Event Warning:{}", json!({ "reason": reason })));
+        } else {
+            warn!("Dropping session {} ({}): {}", self.id, self.ip, reason);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some(reason.to_string()),
+            }));
+            ctx.stop();
         }
+    }
 
-        let join_msg = JoinGame {
-            game_name: game_name.clone(),
-            player: ctx.address().recipient(),
-        };
+    fn send_to_room<M>(&self, msg: M)
+    where
+        M: actix::Message<Result = ()> + Send + 'static,
+        GameRoom: Handler<M>,
+    {
+        if let Some(room) = &self.room {
+            room.do_send(msg);
+        }
+    }
 
+    /// Runs `attempt` only if neither this session nor its address exceeded their
+    /// join and create attempts
+    fn limit_join_attempts<F>(&mut self, ctx: &mut ws::WebsocketContext<Self>, attempt: F)
+    where
+        F: FnOnce(&mut Self, &mut ws::WebsocketContext<Self>) + 'static,
+    {
+        if !self.join_limiter.try_take() {
+            warn!(
+                "Refused join attempt from session {} ({})",
+                self.id, self.ip
+            );
+            ctx.text(join_failed("too many attempts"));
+            return;
+        }
+        ConnectionLimiter::from_registry()
+            .send(JoinAttempt {
+                ip: self.ip.clone(),
+            })
+            .into_actor(self)
+            .then(|result, act, ctx| {
+                match result {
+                    Ok(Ok(())) => attempt(act, ctx),
+                    Ok(Err(reason)) => ctx.text(join_failed(&reason)),
+                    Err(_) => (),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Sends a join, resume or create to the server and enters the room it answers with
+    fn enter_room<M>(&mut self, msg: M, ctx: &mut ws::WebsocketContext<Self>)
+    where
+        M: actix::Message<Result = Result<Joined, String>> + Send + 'static,
+        WsGameServer: Handler<M>,
+    {
         WsGameServer::from_registry()
-            .send(join_msg)
+            .send(msg)
             .into_actor(self)
             .then(|result, act, ctx| {
                 if let Ok(result) = result {
                     match result {
-                        Ok(id) => {
-                            act.id = id;
-                            act.game_name = Some(game_name);
+                        Ok(joined) => {
+                            act.id = joined.player_id;
+                            act.game_name = Some(joined.game_name);
+                            act.room = Some(joined.room);
                         }
                         Err(reason) => {
-                            ctx.text(format!(
-                                "Event JoinGame:{{\"ok\": false,\"reason\":\"{}\"}}",
-                                reason
-                            ));
+                            if reason == "code invalid" {
+                                ConnectionLimiter::from_registry()
+                                    .do_send(InvalidCode { ip: act.ip.clone() });
+                            }
+                            ctx.text(join_failed(&reason));
                         }
                     }
                 }
@@ -79,43 +206,97 @@ impl PlayerSession {
             .wait(ctx);
     }
 
-    pub fn create_game(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+    pub fn join_game(
+        &mut self,
+        game_name: &str,
+        spectator: bool,
+        name: Option<String>,
+        player_type: Option<PlayerType>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let game_name = game_name.to_owned();
+
+        match &self.game_name {
+            Some(game_name) => {
+                let leave_msg = LeaveGame {
+                    game_name: game_name.clone(),
+                    player_id: self.id.clone(),
+                };
+                self.send_to_room(leave_msg);
+            }
+            _ => (),
+        }
+
+        let join_msg = JoinGame {
+            game_name: game_name.clone(),
+            player: ctx.address().recipient(),
+            disconnect: ctx.address().recipient(),
+            spectator,
+            name,
+            account_name: self.account_name(),
+            player_type,
+            account_id: self.account.as_ref().map(|account| account.id.clone()),
+        };
+
+        self.enter_room(join_msg, ctx);
+    }
+
+    /// Takes the place the player had in a room before the server restarted
+    pub fn resume_game(
+        &mut self,
+        game_name: &str,
+        resume_token: String,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if let Some(game_name) = &self.game_name {
+            let leave_msg = LeaveGame {
+                game_name: game_name.clone(),
+                player_id: self.id.clone(),
+            };
+            self.send_to_room(leave_msg);
+        }
+
+        let resume_msg = ResumeGame {
+            game_name: game_name.to_owned(),
+            resume_token,
+            player: ctx.address().recipient(),
+            disconnect: ctx.address().recipient(),
+        };
+
+        self.enter_room(resume_msg, ctx);
+    }
+
+    pub fn create_game(
+        &mut self,
+        name: Option<String>,
+        player_type: Option<PlayerType>,
+        mode: GameMode,
+        duration: Option<Duration>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
         match &self.game_name {
             Some(game_name) => {
                 let leave_msg = LeaveGame {
                     game_name: game_name.clone(),
                     player_id: self.id.clone(),
                 };
-                self.issue_system_sync(leave_msg, ctx);
+                self.send_to_room(leave_msg);
             }
             _ => (),
         }
 
         let create_msg = CreateGame {
             player: ctx.address().recipient(),
+            disconnect: ctx.address().recipient(),
+            name,
+            account_name: self.account_name(),
+            player_type,
+            account_id: self.account.as_ref().map(|account| account.id.clone()),
+            mode,
+            duration,
         };
 
-        WsGameServer::from_registry()
-            .send(create_msg)
-            .into_actor(self)
-            .then(|result, act, ctx| {
-                if let Ok(result) = result {
-                    match result {
-                        Ok((id, game_name)) => {
-                            act.id = id;
-                            act.game_name = Some(game_name);
-                        }
-                        Err(reason) => {
-                            ctx.text(format!(
-                                "Event JoinGame:{{\"ok\": false,\"reason\":\"{}\"}}",
-                                reason
-                            ));
-                        }
-                    }
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
+        self.enter_room(create_msg, ctx);
     }
 
     pub fn send_msg(&self, msg: &str) {
@@ -127,14 +308,13 @@ impl PlayerSession {
                     sender_id: self.id.clone(),
                 };
 
-                // issue_async comes from having the `BrokerIssue` trait in scope.
-                self.issue_system_async(msg);
+                self.send_to_room(msg);
             }
             _ => (),
         }
     }
 
-    pub fn send_game_state(&self, payload: serde_json::Value, secret: String) {
+    pub fn send_game_state(&self, payload: serde_json::Value, secret: Option<String>) {
         match &self.game_name {
             Some(game_name) => {
                 let msg = GameState {
@@ -144,29 +324,283 @@ impl PlayerSession {
                     secret,
                 };
 
-                // issue_async comes from having the `BrokerIssue` trait in scope.
-                self.issue_system_async(msg);
+                self.send_to_room(msg);
             }
             _ => (),
         }
     }
 
-    pub fn send_start_game(&self, secret: String) {
+    pub fn send_start_game(&self, secret: Option<String>, force: bool) {
         match &self.game_name {
             Some(game_name) => {
                 let msg = StartGame {
                     sender_id: self.id.clone(),
                     game_name: game_name.to_owned(),
                     secret,
+                    force,
                 };
 
-                // issue_async comes from having the `BrokerIssue` trait in scope.
-                self.issue_system_async(msg);
+                self.send_to_room(msg);
             }
             _ => (),
         }
     }
 
+    pub fn send_change_color(&self, player_type: PlayerType) {
+        if let Some(game_name) = &self.game_name {
+            let msg = ChangeColor {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                player_type,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_change_skin(&self, skin: Option<Skin>) {
+        if let Some(game_name) = &self.game_name {
+            let msg = ChangeSkin {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                skin,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_ready(&self, ready: bool) {
+        if let Some(game_name) = &self.game_name {
+            let msg = SetReady {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                ready,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_rematch(&self, secret: Option<String>, same_seed: bool) {
+        if let Some(game_name) = &self.game_name {
+            let msg = Rematch {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                secret,
+                same_seed,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_set_team(&self, secret: Option<String>, player_id: String, team: Team) {
+        if let Some(game_name) = &self.game_name {
+            let msg = SetTeam {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                secret,
+                player_id,
+                team,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    /// Leaderboards do not need a game, the answer goes straight back to this session
+    pub fn request_leaderboard(
+        &self,
+        query: LeaderboardQuery,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let kind = query.kind;
+        let offset = query.offset;
+        WsGameServer::from_registry()
+            .send(GetLeaderboard { query })
+            .into_actor(self)
+            .then(move |result, _act, ctx| {
+                match result {
+                    Ok(Ok(entries)) => ctx.text(format!(
+                        "Event Leaderboard:{}",
+                        json!({ "kind": kind, "offset": offset, "entries": entries })
+                    )),
+                    Ok(Err(error)) => warn!("Failed to load leaderboard: {}", error),
+                    Err(_) => (),
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Guests have no achievements, they get an empty list without asking the server
+    pub fn request_achievements(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let reply = |achievements: Vec<Achievement>| {
+            let skins: Vec<Skin> = achievements
+                .iter()
+                .map(|achievement| achievement.skin())
+                .collect();
+            format!(
+                "Event Achievements:{}",
+                json!({ "achievements": achievements, "skins": skins })
+            )
+        };
+        let account_id = match &self.account {
+            Some(account) => account.id.clone(),
+            None => {
+                ctx.text(reply(vec![]));
+                return;
+            }
+        };
+        WsGameServer::from_registry()
+            .send(GetAchievements { account_id })
+            .into_actor(self)
+            .then(move |result, _act, ctx| {
+                match result {
+                    Ok(Ok(achievements)) => ctx.text(reply(achievements)),
+                    Ok(Err(error)) => warn!("Failed to load achievements: {}", error),
+                    Err(_) => (),
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    pub fn send_chat(&self, message: String) {
+        if let Some(game_name) = &self.game_name {
+            let msg = Chat {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                message,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_marker(&self, kind: MarkerKind, position: Coordinates) {
+        if let Some(game_name) = &self.game_name {
+            let msg = PlaceMarker {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                kind,
+                position,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_emote(&self, emote: Emote) {
+        if let Some(game_name) = &self.game_name {
+            let msg = SendEmote {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                emote,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_mute(&self, secret: Option<String>, player_id: String, muted: bool) {
+        if let Some(game_name) = &self.game_name {
+            let msg = MutePlayer {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                secret,
+                player_id,
+                muted,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_kick(&self, secret: Option<String>, player_id: String, reason: String) {
+        if let Some(game_name) = &self.game_name {
+            let msg = KickPlayer {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                secret,
+                player_id,
+                reason,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_transfer_leadership(&self, secret: Option<String>, player_id: String) {
+        if let Some(game_name) = &self.game_name {
+            let msg = TransferLeadership {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                secret,
+                player_id,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_lock_room(&self, secret: Option<String>, locked: bool) {
+        if let Some(game_name) = &self.game_name {
+            let msg = LockRoom {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                secret,
+                locked,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_max_players(&self, secret: Option<String>, max_players: usize) {
+        if let Some(game_name) = &self.game_name {
+            let msg = SetMaxPlayers {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                secret,
+                max_players,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_player_state(
+        &self,
+        state: serde_json::Map<String, serde_json::Value>,
+        damage: f64,
+        target: Option<Team>,
+    ) {
+        if let Some(game_name) = &self.game_name {
+            let msg = PlayerState {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+                state,
+                damage,
+                target,
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
+    pub fn send_player_died(&self) {
+        if let Some(game_name) = &self.game_name {
+            let msg = PlayerDied {
+                sender_id: self.id.clone(),
+                game_name: game_name.to_owned(),
+            };
+
+            self.send_to_room(msg);
+        }
+    }
+
     fn hb(&self, ctx: &mut <Self as Actor>::Context) {
         ctx.run_interval(self.hb.interval, |act, ctx| {
             // check client heartbeats
@@ -175,13 +609,10 @@ impl PlayerSession {
 
                 match &act.game_name {
                     Some(game_name) => {
-                        act.issue_system_sync(
-                            LeaveGame {
-                                game_name: game_name.clone(),
-                                player_id: act.id.clone(),
-                            },
-                            ctx,
-                        );
+                        act.send_to_room(LeaveGame {
+                            game_name: game_name.clone(),
+                            player_id: act.id.clone(),
+                        });
                     }
                     _ => (),
                 }
@@ -190,6 +621,7 @@ impl PlayerSession {
                 return;
             }
 
+            act.hb.last_ping = Instant::now();
             ctx.ping(b"");
         });
     }
@@ -200,18 +632,20 @@ impl Actor for PlayerSession {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+        self.subscribe_system_async::<ServerShutdown>(ctx);
+        self.subscribe_system_async::<CloseSessions>(ctx);
     }
 
-    fn stopped(&mut self, ctx: &mut Self::Context) {
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        ConnectionLimiter::from_registry().do_send(CloseConnection {
+            ip: self.ip.clone(),
+        });
         match &self.game_name {
             Some(game_name) => {
-                self.issue_system_sync(
-                    LeaveGame {
-                        game_name: game_name.clone(),
-                        player_id: self.id.clone(),
-                    },
-                    ctx,
-                );
+                self.send_to_room(LeaveGame {
+                    game_name: game_name.clone(),
+                    player_id: self.id.clone(),
+                });
             }
             _ => (),
         }
@@ -230,6 +664,51 @@ impl Handler<Message> for PlayerSession {
     }
 }
 
+impl Handler<Disconnect> for PlayerSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        // the server already removed us from the game
+        self.game_name = None;
+        self.room = None;
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<ServerShutdown> for PlayerSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerShutdown, ctx: &mut Self::Context) {
+        let now = server_time();
+        ctx.text(format!(
+            "Event ServerShutdown:{}",
+            json!({
+                "deadline": msg.deadline,
+                "secondsLeft": msg.deadline.saturating_sub(now) / 1000,
+            })
+        ));
+    }
+}
+
+impl Handler<CloseSessions> for PlayerSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseSessions, ctx: &mut Self::Context) {
+        // the whole server goes away, there is no game left to leave
+        self.game_name = None;
+        self.room = None;
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("server is shutting down".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PlayerSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
@@ -244,79 +723,335 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PlayerSession {
 
         match msg {
             ws::Message::Text(text) => {
+                if text.len() > self.limits.max_frame_size {
+                    self.violation("frame too large", ctx);
+                    return;
+                }
                 let msg = text.trim();
 
                 if msg.starts_with("Event ") {
                     let mut command = msg.splitn(2, ':');
+                    let event = command.next().unwrap_or_default();
+                    if !self.event_limiter.try_take(event) {
+                        self.violation("too many events", ctx);
+                        return;
+                    }
+                    let payload = match command.next() {
+                        Some(payload) => match self.limits.parse_payload(payload) {
+                            Ok(json) => Some(json),
+                            Err(reason) => {
+                                self.violation(&reason, ctx);
+                                return;
+                            }
+                        },
+                        None => None,
+                    };
 
-                    match command.next() {
-                        Some("Event GameState") => {
-                            if let Some(payload) = command.next() {
-                                let json: serde_json::Value =
-                                    serde_json::from_str(payload).expect("malformed_json");
-                                let json_map =
-                                    json.as_object().expect("malformed_json: not an object");
-                                let secret = json_map.get("secret").expect("No secret").as_str();
-
-                                if let Some(secret) = secret {
-                                    self.send_game_state(json!(json_map), secret.to_string());
-                                }
+                    match event {
+                        "Event GameState" => {
+                            if let Some(mut json_map) = payload {
+                                // the state is relayed to everyone, so it must not carry the secret
+                                let secret = json_map
+                                    .remove("secret")
+                                    .and_then(|secret| secret.as_str().map(str::to_string));
+
+                                self.send_game_state(json!(json_map), secret);
                             }
                         }
-                        Some("Event StartGame") => {
-                            if let Some(payload) = command.next() {
-                                let json: serde_json::Value =
-                                    serde_json::from_str(payload).expect("malformed_json");
-                                let json_map =
-                                    json.as_object().expect("malformed_json: not an object");
-                                let secret = json_map.get("secret").expect("No secret").as_str();
-
-                                if let Some(secret) = secret {
-                                    self.send_start_game(secret.to_string());
-                                }
+                        "Event StartGame" => {
+                            if let Some(json_map) = payload {
+                                let secret = json_map
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+
+                                let force = json_map
+                                    .get("force")
+                                    .and_then(|force| force.as_bool())
+                                    .unwrap_or(false);
+
+                                self.send_start_game(secret, force);
                             }
                         }
-                        Some("Event PlayerState") => {
-                            if let Some(payload) = command.next() {
-                                let mut json: serde_json::Value =
-                                    serde_json::from_str(payload).expect("malformed_json");
-                                json.as_object_mut()
-                                    .expect("malformed_json: not an object")
-                                    .insert(
-                                        String::from("playerId"),
-                                        serde_json::Value::String(self.id.to_string()),
-                                    );
-                                self.send_msg(&format!("Event PlayerState:{}", &json.to_string()));
+                        "Event PlayerState" => {
+                            if let Some(mut json) = payload {
+                                let damage = json
+                                    .get("damageDealt")
+                                    .and_then(|damage| damage.as_f64())
+                                    .unwrap_or(0.);
+                                let target = json
+                                    .get("damageTarget")
+                                    .and_then(|target| serde_json::from_value(target.clone()).ok());
+                                let dead = json
+                                    .get("dead")
+                                    .and_then(|dead| dead.as_bool())
+                                    .unwrap_or(false);
+                                json.insert(
+                                    String::from("playerId"),
+                                    serde_json::Value::String(self.id.to_string()),
+                                );
+                                self.send_player_state(json, damage, target);
+                                if dead && !self.dead {
+                                    self.send_player_died();
+                                }
+                                self.dead = dead;
                             }
                         }
-                        Some("Event JoinGame") => {
-                            if let Some(payload) = command.next() {
-                                let json: serde_json::Value =
-                                    serde_json::from_str(payload).expect("malformed_json");
-                                let json_map =
-                                    json.as_object().expect("malformed_json: not an object");
-                                let code = json_map.get("code").expect("No game code").as_str();
+                        "Event JoinGame" => {
+                            if let Some(json_map) = payload {
+                                let code = json_map.get("code").and_then(|code| code.as_str());
                                 match code {
                                     Some(code) => {
-                                        let chars: Vec<char> = code.chars().collect();
-                                        if chars.len() != 5
-                                            || chars.iter().any(|single_char| -> bool {
-                                                !single_char.is_alphanumeric()
-                                            })
-                                        {
-                                            ctx.text("Event JoinGame:{\"ok\": false,\"reason\":\"code invalid\"}");
-                                            return;
-                                        }
-                                        self.join_game(&code, ctx);
+                                        let spectator = json_map
+                                            .get("spectate")
+                                            .and_then(|spectate| spectate.as_bool())
+                                            .unwrap_or(false);
+                                        let name = json_map
+                                            .get("name")
+                                            .and_then(|name| name.as_str())
+                                            .map(String::from);
+                                        let player_type =
+                                            json_map.get("playerType").and_then(|player_type| {
+                                                serde_json::from_value::<PlayerType>(
+                                                    player_type.clone(),
+                                                )
+                                                .ok()
+                                            });
+                                        let code = code.to_string();
+                                        self.limit_join_attempts(ctx, move |act, ctx| {
+                                            act.join_game(&code, spectator, name, player_type, ctx)
+                                        });
                                     }
                                     _ => (),
                                 };
                             }
                         }
-                        Some("Event CreateGame") => {
-                            self.create_game(ctx);
+                        "Event ResumeGame" => {
+                            let json = payload.unwrap_or_default();
+                            let code = json.get("code").and_then(|code| code.as_str());
+                            let token = json.get("token").and_then(|token| token.as_str());
+                            if let (Some(code), Some(token)) = (code, token) {
+                                let code = code.to_string();
+                                let token = token.to_string();
+                                self.limit_join_attempts(ctx, move |act, ctx| {
+                                    act.resume_game(&code, token, ctx)
+                                });
+                            }
+                        }
+                        "Event CreateGame" => {
+                            let json = payload.unwrap_or_default();
+                            let mode = json
+                                .get("mode")
+                                .and_then(|mode| {
+                                    serde_json::from_value::<GameMode>(mode.clone()).ok()
+                                })
+                                .unwrap_or_default();
+                            let duration = json
+                                .get("duration")
+                                .and_then(|duration| duration.as_u64())
+                                .map(Duration::from_secs);
+                            let name = json
+                                .get("name")
+                                .and_then(|name| name.as_str())
+                                .map(String::from);
+                            let player_type = json.get("playerType").and_then(|player_type| {
+                                serde_json::from_value::<PlayerType>(player_type.clone()).ok()
+                            });
+                            self.limit_join_attempts(ctx, move |act, ctx| {
+                                act.create_game(name, player_type, mode, duration, ctx)
+                            });
+                        }
+                        "Event ChangeColor" => {
+                            if let Some(json) = payload {
+                                if let Some(player_type) =
+                                    json.get("playerType").and_then(|player_type| {
+                                        serde_json::from_value::<PlayerType>(player_type.clone())
+                                            .ok()
+                                    })
+                                {
+                                    self.send_change_color(player_type);
+                                }
+                            }
+                        }
+                        "Event ChangeSkin" => {
+                            if let Some(json) = payload {
+                                if let Ok(skin) = serde_json::from_value::<Option<Skin>>(
+                                    json.get("skin").cloned().unwrap_or_default(),
+                                ) {
+                                    self.send_change_skin(skin);
+                                }
+                            }
+                        }
+                        "Event Achievements" => {
+                            self.request_achievements(ctx);
+                        }
+                        "Event Ready" => {
+                            self.send_ready(ready_flag(payload.as_ref()));
+                        }
+                        "Event Rematch" => {
+                            if let Some(json) = payload {
+                                let secret = json
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+                                let same_seed = json
+                                    .get("sameSeed")
+                                    .and_then(|same_seed| same_seed.as_bool())
+                                    .unwrap_or(false);
+
+                                self.send_rematch(secret, same_seed);
+                            }
+                        }
+                        "Event SetTeam" => {
+                            if let Some(json) = payload {
+                                let secret = json
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+                                let player_id = json
+                                    .get("playerId")
+                                    .and_then(|player_id| player_id.as_str());
+                                let team = json.get("team").and_then(|team| {
+                                    serde_json::from_value::<Team>(team.clone()).ok()
+                                });
+
+                                if let (Some(player_id), Some(team)) = (player_id, team) {
+                                    self.send_set_team(secret, player_id.to_string(), team);
+                                }
+                            }
+                        }
+                        "Event Chat" => {
+                            if let Some(json) = payload {
+                                if let Some(message) =
+                                    json.get("message").and_then(|message| message.as_str())
+                                {
+                                    self.send_chat(message.to_string());
+                                }
+                            }
+                        }
+                        "Event Marker" => {
+                            if let Some(json) = payload {
+                                let kind = json.get("kind").and_then(|kind| {
+                                    serde_json::from_value::<MarkerKind>(kind.clone()).ok()
+                                });
+                                let position = json.get("position").and_then(|position| {
+                                    serde_json::from_value::<Coordinates>(position.clone()).ok()
+                                });
+
+                                if let (Some(kind), Some(position)) = (kind, position) {
+                                    self.send_marker(kind, position);
+                                }
+                            }
+                        }
+                        "Event Emote" => {
+                            if let Some(json) = payload {
+                                if let Some(emote) = json.get("emote").and_then(|emote| {
+                                    serde_json::from_value::<Emote>(emote.clone()).ok()
+                                }) {
+                                    self.send_emote(emote);
+                                }
+                            }
+                        }
+                        "Event Mute" => {
+                            if let Some(json) = payload {
+                                let secret = json
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+                                let player_id = json
+                                    .get("playerId")
+                                    .and_then(|player_id| player_id.as_str());
+                                let muted = json
+                                    .get("muted")
+                                    .and_then(|muted| muted.as_bool())
+                                    .unwrap_or(true);
+
+                                if let Some(player_id) = player_id {
+                                    self.send_mute(secret, player_id.to_string(), muted);
+                                }
+                            }
+                        }
+                        "Event Kick" => {
+                            if let Some(json) = payload {
+                                let secret = json
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+                                let player_id = json
+                                    .get("playerId")
+                                    .and_then(|player_id| player_id.as_str());
+                                let reason = json
+                                    .get("reason")
+                                    .and_then(|reason| reason.as_str())
+                                    .unwrap_or("kicked by the room leader");
+
+                                if let Some(player_id) = player_id {
+                                    self.send_kick(
+                                        secret,
+                                        player_id.to_string(),
+                                        reason.to_string(),
+                                    );
+                                }
+                            }
+                        }
+                        "Event TransferLeadership" => {
+                            if let Some(json) = payload {
+                                let secret = json
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+                                let player_id = json
+                                    .get("playerId")
+                                    .and_then(|player_id| player_id.as_str());
+
+                                if let Some(player_id) = player_id {
+                                    self.send_transfer_leadership(secret, player_id.to_string());
+                                }
+                            }
+                        }
+                        "Event LockRoom" => {
+                            if let Some(json) = payload {
+                                let secret = json
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+                                let locked = json
+                                    .get("locked")
+                                    .and_then(|locked| locked.as_bool())
+                                    .unwrap_or(true);
+
+                                self.send_lock_room(secret, locked);
+                            }
+                        }
+                        "Event SetMaxPlayers" => {
+                            if let Some(json) = payload {
+                                let secret = json
+                                    .get("secret")
+                                    .and_then(|secret| secret.as_str())
+                                    .map(str::to_string);
+                                let max_players = json
+                                    .get("maxPlayers")
+                                    .and_then(|max_players| max_players.as_u64());
+
+                                if let Some(max_players) = max_players {
+                                    self.send_max_players(secret, max_players as usize);
+                                }
+                            }
                         }
-                        Some("Event Ping") => {
+                        "Event Leaderboard" => {
+                            if let Some(json_map) = payload {
+                                match serde_json::from_value::<LeaderboardQuery>(
+                                    serde_json::Value::Object(json_map),
+                                ) {
+                                    Ok(query) => self.request_leaderboard(query, ctx),
+                                    Err(error) => ctx.text(format!(
+                                        "Event Warning:{}",
+                                        json!({ "reason": error.to_string() })
+                                    )),
+                                }
+                            }
+                        }
+                        "Event Ping" => {
                             ctx.text(msg);
                         }
                         _ => ctx.text(format!("!!! unknown event: {:?}", msg)),
@@ -328,9 +1063,31 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PlayerSession {
                 ctx.stop();
             }
             ws::Message::Pong(_bytes) => {
-                self.hb.last_client_hb = Instant::now();
+                let latency = self.hb.pong();
+                if let Some(game_name) = &self.game_name {
+                    self.send_to_room(ReportLatency {
+                        game_name: game_name.clone(),
+                        sender_id: self.id.clone(),
+                        latency,
+                    });
+                }
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_the_ready_flag() {
+        let limits = InboundLimits::default();
+        let payload = limits.parse_payload(r#"{"ready":false}"#).unwrap();
+        assert!(!ready_flag(Some(&payload)));
+        let payload = limits.parse_payload(r#"{"ready":true}"#).unwrap();
+        assert!(ready_flag(Some(&payload)));
+        assert!(ready_flag(None));
+    }
+}
@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use crate::achievements::Achievement;
+use crate::server::{GameMode, MatchOutcome, PlayerType, Team};
+
+mod sqlite;
+
+pub use sqlite::SqliteStore;
+
+/// A finished match as it is kept in storage
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchRecord {
+    /// Assigned by the store
+    pub id: Option<i64>,
+    pub code: String,
+    pub seed: u32,
+    pub mode: GameMode,
+    /// JSON description of the map the match was played on
+    pub map_config: String,
+    /// Milliseconds since the unix epoch
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub outcome: MatchOutcome,
+    pub winner: Option<Team>,
+    pub players: Vec<PlayerRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerRecord {
+    pub player_id: String,
+    /// Guests have no account
+    pub account_id: Option<String>,
+    pub name: String,
+    pub player_type: PlayerType,
+    pub team: Team,
+    pub damage_dealt: f64,
+    pub deaths: u32,
+    /// Whether the replay of the match backs up the damage and deaths of the player
+    pub verified: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LeaderboardKind {
+    /// Shortest won matches
+    FASTESTKILL,
+    /// Total damage dealt to enemy planets
+    DAMAGE,
+    /// Longest run of won matches
+    WINSTREAK,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PlayerCountBucket {
+    SOLO,
+    DUO,
+    /// Three or four players
+    SQUAD,
+    /// Five players or more
+    CROWD,
+}
+
+impl PlayerCountBucket {
+    fn range(self) -> (i64, i64) {
+        match self {
+            PlayerCountBucket::SOLO => (1, 1),
+            PlayerCountBucket::DUO => (2, 2),
+            PlayerCountBucket::SQUAD => (3, 4),
+            PlayerCountBucket::CROWD => (5, i64::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderboardQuery {
+    pub kind: LeaderboardKind,
+    pub seed: Option<u32>,
+    pub mode: Option<GameMode>,
+    pub players: Option<PlayerCountBucket>,
+    /// Only matches started at or after this server time in ms
+    pub since: Option<u64>,
+    /// Only matches started before this server time in ms
+    pub until: Option<u64>,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "LeaderboardQuery::default_limit")]
+    pub limit: usize,
+}
+
+impl LeaderboardQuery {
+    pub const MAX_LIMIT: usize = 100;
+
+    fn default_limit() -> usize {
+        10
+    }
+}
+
+/// Players are ranked by account, guests by the player id of their session
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub account_id: Option<String>,
+    pub name: String,
+    /// Milliseconds, damage or won matches depending on the leaderboard
+    pub value: f64,
+}
+
+pub trait MatchStore: Send {
+    /// Stores a finished match and returns its id
+    fn record_match(&mut self, record: &MatchRecord) -> Result<i64, String>;
+
+    /// Returns the latest matches, newest first
+    fn recent_matches(&self, limit: usize) -> Result<Vec<MatchRecord>, String>;
+
+    fn leaderboard(&self, query: &LeaderboardQuery) -> Result<Vec<LeaderboardEntry>, String>;
+
+    /// Grants an achievement, returns false if the account already had it
+    fn unlock(
+        &mut self,
+        account_id: &str,
+        achievement: Achievement,
+        unlocked_at: u64,
+    ) -> Result<bool, String>;
+
+    /// Returns the achievements of an account in the order they were unlocked
+    fn achievements(&self, account_id: &str) -> Result<Vec<Achievement>, String>;
+
+    /// Damage an account dealt over all recorded matches that verified it
+    fn total_damage(&self, account_id: &str) -> Result<f64, String>;
+}
@@ -0,0 +1,553 @@
+use std::cmp::Ordering;
+use std::path::Path;
+
+use rusqlite::{params, Connection, Row, NO_PARAMS};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::achievements::Achievement;
+use crate::storage::{
+    LeaderboardEntry, LeaderboardKind, LeaderboardQuery, MatchRecord, MatchStore, PlayerRecord,
+};
+
+/// Every entry upgrades the schema by one version. Entries must never be changed
+/// once released, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE matches (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        code TEXT NOT NULL,
+        seed INTEGER NOT NULL,
+        mode TEXT NOT NULL,
+        map_config TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        outcome TEXT NOT NULL,
+        winner TEXT
+    );
+    CREATE TABLE match_players (
+        match_id INTEGER NOT NULL REFERENCES matches(id) ON DELETE CASCADE,
+        player_id TEXT NOT NULL,
+        account_id TEXT,
+        name TEXT NOT NULL,
+        player_type TEXT NOT NULL,
+        team TEXT NOT NULL,
+        damage_dealt REAL NOT NULL
+    );
+    CREATE INDEX match_players_match ON match_players(match_id);
+    CREATE INDEX match_players_account ON match_players(account_id);
+",
+    "
+    ALTER TABLE match_players ADD COLUMN deaths INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE unlocks (
+        account_id TEXT NOT NULL,
+        achievement TEXT NOT NULL,
+        unlocked_at INTEGER NOT NULL,
+        PRIMARY KEY (account_id, achievement)
+    );
+",
+    "
+    ALTER TABLE match_players ADD COLUMN verified INTEGER NOT NULL DEFAULT 0;
+",
+];
+
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        Self::with_connection(Connection::open(path).map_err(|error| error.to_string())?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, String> {
+        Self::with_connection(Connection::open_in_memory().map_err(|error| error.to_string())?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, String> {
+        connection
+            .execute_batch("PRAGMA foreign_keys = ON;")
+            .map_err(|error| error.to_string())?;
+        migrate(&mut connection)?;
+        Ok(SqliteStore { connection })
+    }
+
+    fn players(&self, match_id: i64) -> rusqlite::Result<Vec<PlayerRecord>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT player_id, account_id, name, player_type, team, damage_dealt, deaths,
+                    verified
+             FROM match_players WHERE match_id = ?1 ORDER BY rowid",
+        )?;
+        let players = statement.query_map(params![match_id], |row| {
+            Ok(PlayerRecord {
+                player_id: row.get(0)?,
+                account_id: row.get(1)?,
+                name: row.get(2)?,
+                player_type: from_name(row, 3)?,
+                team: from_name(row, 4)?,
+                damage_dealt: row.get(5)?,
+                deaths: row.get(6)?,
+                verified: row.get(7)?,
+            })
+        })?;
+        players.collect()
+    }
+}
+
+/// One row per player of every match that passes the filters of a leaderboard query.
+/// A player won if the match was won and, in a race, their team was the winner.
+const LEADERBOARD_ROWS: &str = "
+    WITH filtered AS (
+        SELECT
+            matches.id AS match_id,
+            matches.started_at,
+            matches.duration_ms,
+            COALESCE(players.account_id, players.player_id) AS identity,
+            players.account_id,
+            players.name,
+            players.damage_dealt,
+            matches.outcome = 'WON'
+                AND (matches.winner IS NULL OR matches.winner = players.team) AS won,
+            (SELECT COUNT(*) FROM match_players counted
+             WHERE counted.match_id = matches.id) AS player_count
+        FROM matches JOIN match_players players ON players.match_id = matches.id
+        WHERE (?1 IS NULL OR matches.seed = ?1)
+            AND (?2 IS NULL OR matches.mode = ?2)
+            AND (?3 IS NULL OR matches.started_at >= ?3)
+            AND (?4 IS NULL OR matches.started_at < ?4)
+    )
+    SELECT * FROM filtered WHERE player_count BETWEEN ?5 AND ?6
+";
+
+/// Applies all migrations newer than the version recorded in the database
+fn migrate(connection: &mut Connection) -> Result<(), String> {
+    let version: i64 = connection
+        .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+        .map_err(|error| error.to_string())?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = connection
+            .transaction()
+            .map_err(|error| error.to_string())?;
+        transaction
+            .execute_batch(migration)
+            .and_then(|_| {
+                transaction.execute_batch(&format!("PRAGMA user_version = {}", index + 1))
+            })
+            .and_then(|_| transaction.commit())
+            .map_err(|error| format!("migration {} failed: {}", index + 1, error))?;
+    }
+    Ok(())
+}
+
+/// Enums are stored by their serialized name, e.g. `BLUE`
+fn to_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+fn from_name<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let name: String = row.get(index)?;
+    serde_json::from_value(serde_json::Value::String(name)).map_err(|error| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index,
+            rusqlite::types::Type::Text,
+            Box::new(error),
+        )
+    })
+}
+
+impl MatchStore for SqliteStore {
+    fn record_match(&mut self, record: &MatchRecord) -> Result<i64, String> {
+        let transaction = self
+            .connection
+            .transaction()
+            .map_err(|error| error.to_string())?;
+        transaction
+            .execute(
+                "INSERT INTO matches
+                 (code, seed, mode, map_config, started_at, duration_ms, outcome, winner)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    record.code,
+                    record.seed,
+                    to_name(&record.mode),
+                    record.map_config,
+                    record.started_at as i64,
+                    record.duration_ms as i64,
+                    to_name(&record.outcome),
+                    record.winner.as_ref().map(to_name),
+                ],
+            )
+            .map_err(|error| error.to_string())?;
+        let match_id = transaction.last_insert_rowid();
+        for player in &record.players {
+            transaction
+                .execute(
+                    "INSERT INTO match_players
+                     (match_id, player_id, account_id, name, player_type, team, damage_dealt,
+                      deaths, verified)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        match_id,
+                        player.player_id,
+                        player.account_id,
+                        player.name,
+                        to_name(&player.player_type),
+                        to_name(&player.team),
+                        player.damage_dealt,
+                        player.deaths,
+                        player.verified,
+                    ],
+                )
+                .map_err(|error| error.to_string())?;
+        }
+        transaction.commit().map_err(|error| error.to_string())?;
+        Ok(match_id)
+    }
+
+    fn leaderboard(&self, query: &LeaderboardQuery) -> Result<Vec<LeaderboardEntry>, String> {
+        let limit = query.limit.min(LeaderboardQuery::MAX_LIMIT);
+        let (min_players, max_players) = query
+            .players
+            .map(|players| players.range())
+            .unwrap_or((0, i64::MAX));
+        let ranking = match query.kind {
+            // SQLite takes the bare columns from the row with the minimum
+            LeaderboardKind::FASTESTKILL => {
+                "SELECT account_id, name, MIN(duration_ms) AS value FROM rows
+                 WHERE won GROUP BY identity ORDER BY value ASC, MIN(started_at) ASC"
+            }
+            LeaderboardKind::DAMAGE => {
+                "SELECT account_id, name, SUM(damage_dealt) AS value FROM rows
+                 GROUP BY identity ORDER BY value DESC, MIN(started_at) ASC"
+            }
+            // streaks are counted in order of play, which SQL can not do without window functions
+            LeaderboardKind::WINSTREAK => {
+                "SELECT account_id, name, won, identity FROM rows ORDER BY identity, started_at"
+            }
+        };
+        let sql = format!(
+            "WITH rows AS ({}) {} LIMIT ?7 OFFSET ?8",
+            LEADERBOARD_ROWS, ranking
+        );
+        let (sql_limit, sql_offset) = match query.kind {
+            LeaderboardKind::WINSTREAK => (-1, 0),
+            _ => (limit as i64, query.offset as i64),
+        };
+        let mut statement = self
+            .connection
+            .prepare(&sql)
+            .map_err(|error| error.to_string())?;
+        let parameters = params![
+            query.seed,
+            query.mode.as_ref().map(to_name),
+            query.since.map(|since| since as i64),
+            query.until.map(|until| until as i64),
+            min_players,
+            max_players,
+            sql_limit,
+            sql_offset,
+        ];
+        let entries = match query.kind {
+            LeaderboardKind::WINSTREAK => {
+                let rows = statement
+                    .query_map(parameters, |row| {
+                        Ok((
+                            row.get::<_, Option<String>>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, bool>(2)?,
+                            row.get::<_, String>(3)?,
+                        ))
+                    })
+                    .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                    .map_err(|error| error.to_string())?;
+                let mut streaks: Vec<LeaderboardEntry> = vec![];
+                let mut current = 0;
+                for (index, (account_id, name, won, identity)) in rows.iter().enumerate() {
+                    if index == 0 || rows[index - 1].3 != *identity {
+                        current = 0;
+                        streaks.push(LeaderboardEntry {
+                            rank: 0,
+                            account_id: account_id.clone(),
+                            name: name.clone(),
+                            value: 0.,
+                        });
+                    }
+                    current = if *won { current + 1 } else { 0 };
+                    if let Some(entry) = streaks.last_mut() {
+                        entry.name = name.clone();
+                        entry.value = entry.value.max(current as f64);
+                    }
+                }
+                // the sort is stable, so ties keep the order of their identities
+                streaks.sort_by(|a, b| b.value.partial_cmp(&a.value).unwrap_or(Ordering::Equal));
+                streaks
+                    .into_iter()
+                    .filter(|entry| entry.value > 0.)
+                    .skip(query.offset)
+                    .take(limit)
+                    .collect()
+            }
+            _ => statement
+                .query_map(parameters, |row| {
+                    Ok(LeaderboardEntry {
+                        rank: 0,
+                        account_id: row.get(0)?,
+                        name: row.get(1)?,
+                        value: row.get(2)?,
+                    })
+                })
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                .map_err(|error| error.to_string())?,
+        };
+        Ok(entries
+            .into_iter()
+            .enumerate()
+            .map(|(index, entry)| LeaderboardEntry {
+                rank: query.offset + index + 1,
+                ..entry
+            })
+            .collect())
+    }
+
+    fn recent_matches(&self, limit: usize) -> Result<Vec<MatchRecord>, String> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT id, code, seed, mode, map_config, started_at, duration_ms, outcome, winner
+                 FROM matches ORDER BY id DESC LIMIT ?1",
+            )
+            .map_err(|error| error.to_string())?;
+        let matches = statement
+            .query_map(params![limit as i64], |row| {
+                let winner: Option<String> = row.get(8)?;
+                Ok(MatchRecord {
+                    id: Some(row.get(0)?),
+                    code: row.get(1)?,
+                    seed: row.get(2)?,
+                    mode: from_name(row, 3)?,
+                    map_config: row.get(4)?,
+                    started_at: row.get::<_, i64>(5)? as u64,
+                    duration_ms: row.get::<_, i64>(6)? as u64,
+                    outcome: from_name(row, 7)?,
+                    winner: match winner {
+                        Some(_) => Some(from_name(row, 8)?),
+                        None => None,
+                    },
+                    players: vec![],
+                })
+            })
+            .and_then(|matches| matches.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(|error| error.to_string())?;
+        matches
+            .into_iter()
+            .map(|mut record| {
+                record.players = self
+                    .players(record.id.unwrap_or_default())
+                    .map_err(|error| error.to_string())?;
+                Ok(record)
+            })
+            .collect()
+    }
+
+    fn unlock(
+        &mut self,
+        account_id: &str,
+        achievement: Achievement,
+        unlocked_at: u64,
+    ) -> Result<bool, String> {
+        self.connection
+            .execute(
+                "INSERT OR IGNORE INTO unlocks (account_id, achievement, unlocked_at)
+                 VALUES (?1, ?2, ?3)",
+                params![account_id, to_name(&achievement), unlocked_at as i64],
+            )
+            .map(|inserted| inserted > 0)
+            .map_err(|error| error.to_string())
+    }
+
+    fn achievements(&self, account_id: &str) -> Result<Vec<Achievement>, String> {
+        let mut statement = self
+            .connection
+            .prepare_cached(
+                "SELECT achievement FROM unlocks WHERE account_id = ?1
+                 ORDER BY unlocked_at, rowid",
+            )
+            .map_err(|error| error.to_string())?;
+        statement
+            .query_map(params![account_id], |row| from_name(row, 0))
+            .and_then(|achievements| achievements.collect())
+            .map_err(|error| error.to_string())
+    }
+
+    fn total_damage(&self, account_id: &str) -> Result<f64, String> {
+        self.connection
+            .query_row(
+                "SELECT COALESCE(SUM(damage_dealt), 0) FROM match_players
+                 WHERE account_id = ?1 AND verified",
+                params![account_id],
+                |row| row.get(0),
+            )
+            .map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{GameMode, MatchOutcome, PlayerType, Team};
+
+    fn record() -> MatchRecord {
+        MatchRecord {
+            id: None,
+            code: "ACDEF".to_string(),
+            seed: 42,
+            mode: GameMode::RACE,
+            map_config: "{}".to_string(),
+            started_at: 1_600_000_000_000,
+            duration_ms: 90_000,
+            outcome: MatchOutcome::WON,
+            winner: Some(Team::BETA),
+            players: vec![PlayerRecord {
+                player_id: "1".to_string(),
+                account_id: None,
+                name: "Pilot 1".to_string(),
+                player_type: PlayerType::RED,
+                team: Team::BETA,
+                damage_dealt: 12.5,
+                deaths: 2,
+                verified: false,
+            }],
+        }
+    }
+
+    fn query(kind: LeaderboardKind) -> LeaderboardQuery {
+        LeaderboardQuery {
+            kind,
+            seed: None,
+            mode: None,
+            players: None,
+            since: None,
+            until: None,
+            offset: 0,
+            limit: 10,
+        }
+    }
+
+    fn player(player_id: &str, team: Team, damage_dealt: f64) -> PlayerRecord {
+        PlayerRecord {
+            player_id: player_id.to_string(),
+            account_id: None,
+            name: format!("Pilot {}", player_id),
+            player_type: PlayerType::RED,
+            team,
+            damage_dealt,
+            deaths: 0,
+            verified: true,
+        }
+    }
+
+    #[test]
+    fn ranks_leaderboards() {
+        let mut store = SqliteStore::in_memory().unwrap();
+        let race = |started_at, duration_ms, winner| MatchRecord {
+            started_at,
+            duration_ms,
+            winner: Some(winner),
+            players: vec![player("1", Team::ALPHA, 30.), player("2", Team::BETA, 20.)],
+            ..record()
+        };
+        store.record_match(&race(1, 60_000, Team::ALPHA)).unwrap();
+        store.record_match(&race(2, 50_000, Team::BETA)).unwrap();
+        store.record_match(&race(3, 70_000, Team::BETA)).unwrap();
+
+        let fastest = store
+            .leaderboard(&query(LeaderboardKind::FASTESTKILL))
+            .unwrap();
+        assert_eq!(fastest[0].name, "Pilot 2");
+        assert_eq!(fastest[0].value, 50_000.);
+        assert_eq!(fastest[1].name, "Pilot 1");
+
+        let damage = store.leaderboard(&query(LeaderboardKind::DAMAGE)).unwrap();
+        assert_eq!(damage[0].name, "Pilot 1");
+        assert_eq!(damage[0].value, 90.);
+
+        let streaks = store
+            .leaderboard(&query(LeaderboardKind::WINSTREAK))
+            .unwrap();
+        assert_eq!(streaks[0].name, "Pilot 2");
+        assert_eq!(streaks[0].value, 2.);
+        assert_eq!(streaks[1].rank, 2);
+
+        let page = store
+            .leaderboard(&LeaderboardQuery {
+                offset: 1,
+                since: Some(2),
+                ..query(LeaderboardKind::DAMAGE)
+            })
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].rank, 2);
+        assert_eq!(page[0].value, 40.);
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let mut store = SqliteStore::in_memory().unwrap();
+        migrate(&mut store.connection).unwrap();
+        let version: i64 = store
+            .connection
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn unlocks_achievements_once() {
+        let mut store = SqliteStore::in_memory().unwrap();
+        assert!(store.unlock("a", Achievement::FIRSTWIN, 1).unwrap());
+        assert!(!store.unlock("a", Achievement::FIRSTWIN, 2).unwrap());
+        assert!(store.unlock("a", Achievement::FLAWLESS, 3).unwrap());
+        assert_eq!(
+            store.achievements("a").unwrap(),
+            vec![Achievement::FIRSTWIN, Achievement::FLAWLESS]
+        );
+        assert_eq!(store.achievements("b").unwrap(), vec![]);
+
+        let mut accounted = player("1", Team::ALPHA, 30.);
+        accounted.account_id = Some("a".to_string());
+        let match_record = MatchRecord {
+            players: vec![accounted, player("2", Team::BETA, 20.)],
+            ..record()
+        };
+        store.record_match(&match_record).unwrap();
+        store.record_match(&match_record).unwrap();
+        let mut unverified = match_record.clone();
+        unverified.players[0].verified = false;
+        store.record_match(&unverified).unwrap();
+        assert_eq!(store.total_damage("a").unwrap(), 60.);
+        assert_eq!(store.total_damage("b").unwrap(), 0.);
+    }
+
+    #[test]
+    fn records_and_reads_matches() {
+        let mut store = SqliteStore::in_memory().unwrap();
+        let first = store.record_match(&record()).unwrap();
+        let second = store.record_match(&record()).unwrap();
+
+        let matches = store.recent_matches(10).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].id, Some(second));
+        assert_eq!(matches[1].id, Some(first));
+        assert_eq!(
+            MatchRecord {
+                id: None,
+                ..matches[0].clone()
+            },
+            record()
+        );
+    }
+}
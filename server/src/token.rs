@@ -0,0 +1,50 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+const PLAYER_ID_BYTES: usize = 16;
+const SECRET_BYTES: usize = 32;
+
+/// Issues a public player id, unique enough to be handed out without collision checks
+pub fn player_id() -> String {
+    random_hex(PLAYER_ID_BYTES)
+}
+
+/// Issues a secret which authorizes leader commands
+pub fn secret() -> String {
+    random_hex(SECRET_BYTES)
+}
+
+/// Compares two secrets in time independent of where they first differ
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn random_hex(length: usize) -> String {
+    let mut bytes = vec![0u8; length];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokens_have_expected_length_and_differ() {
+        assert_eq!(player_id().len(), PLAYER_ID_BYTES * 2);
+        assert_eq!(secret().len(), SECRET_BYTES * 2);
+        assert_ne!(secret(), secret());
+    }
+
+    #[test]
+    fn compares_secrets() {
+        let secret = secret();
+        assert!(constant_time_eq(&secret, &secret.clone()));
+        assert!(!constant_time_eq(&secret, &secret[1..]));
+        assert!(!constant_time_eq("abcd", "abce"));
+    }
+}